@@ -1,2 +1,2 @@
 #!/bin/bash
-cargo run 127.0.0.1 6543 $@
+cargo run --features cli -- -s udp:127.0.0.1:6543 "$@"
@@ -1,2 +1,2 @@
 #!/bin/bash
-cargo run 127.0.0.1 65432 $@
+cargo run --features cli -- -s udp:127.0.0.1:65432 "$@"
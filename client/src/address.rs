@@ -0,0 +1,96 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Where to find the server
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerAddress {
+    Udp(String),
+    Tcp(String),
+    Unix(PathBuf),
+    UnixDatagram(PathBuf),
+}
+
+impl FromStr for ServerAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ServerAddress, String> {
+        let address = match s.split_once(':') {
+            Some(("udp", address)) => ServerAddress::Udp(String::from(address)),
+            Some(("tcp", address)) => ServerAddress::Tcp(String::from(address)),
+            Some(("unix", path)) => ServerAddress::Unix(PathBuf::from(path)),
+            Some(("unixgram", path)) => ServerAddress::UnixDatagram(PathBuf::from(path)),
+            // A bare HOST:PORT only has the colon before the port outside of
+            // brackets, anything with more starts with a scheme
+            Some((scheme, _)) if colons_outside_brackets(s) > 1 => {
+                return Err(format!("unknown scheme '{}', use udp:, tcp:, unix: or unixgram:", scheme));
+            },
+            _ => ServerAddress::Udp(String::from(s)),
+        };
+
+        match address {
+            ServerAddress::Udp(ref host_port) | ServerAddress::Tcp(ref host_port) => {
+                let port = host_port.rsplit_once(':').map(|(_, port)| port.parse::<u16>());
+                if !matches!(port, Some(Ok(_))) {
+                    return Err(format!("'{}' needs to be HOST:PORT", host_port));
+                }
+            },
+            ServerAddress::Unix(ref path) | ServerAddress::UnixDatagram(ref path) => {
+                if path.as_os_str().is_empty() {
+                    return Err(String::from("unix sockets need a path"));
+                }
+            },
+        }
+        Ok(address)
+    }
+}
+
+/// Number of colons in `s` that are not part of a bracketed IPv6 address
+fn colons_outside_brackets(s: &str) -> usize {
+    let mut depth = 0;
+    let mut colons = 0;
+    for c in s.chars() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ':' if depth == 0 => colons += 1,
+            _ => (),
+        }
+    }
+    colons
+}
+
+impl fmt::Display for ServerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerAddress::Udp(ref address) => write!(f, "udp:{}", address),
+            ServerAddress::Tcp(ref address) => write!(f, "tcp:{}", address),
+            ServerAddress::Unix(ref path) => write!(f, "unix:{}", path.display()),
+            ServerAddress::UnixDatagram(ref path) => write!(f, "unixgram:{}", path.display()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_address() {
+        let parse = |s: &str| s.parse::<ServerAddress>();
+        assert_eq!(parse("localhost:65432"), Ok(ServerAddress::Udp(String::from("localhost:65432"))));
+        assert_eq!(parse("[::1]:7000"), Ok(ServerAddress::Udp(String::from("[::1]:7000"))));
+        assert_eq!(parse("tcp:127.0.0.1:7000"), Ok(ServerAddress::Tcp(String::from("127.0.0.1:7000"))));
+        assert_eq!(parse("unixgram:/tmp/fancy.sock"),
+                   Ok(ServerAddress::UnixDatagram(PathBuf::from("/tmp/fancy.sock"))));
+        assert!(parse("tcp:localhost").is_err());
+        assert!(parse("localhost:http").is_err());
+        assert!(parse("unix:").is_err());
+
+        // Typos in the scheme are not taken for a host name
+        assert_eq!(parse("tpc:host:7000"), Err(String::from("unknown scheme 'tpc', use udp:, tcp:, unix: or unixgram:")));
+        assert!(parse("udp6:[::1]:7000").is_err());
+        assert_eq!(parse("[fe80::1]:7000"), Ok(ServerAddress::Udp(String::from("[fe80::1]:7000"))));
+    }
+}
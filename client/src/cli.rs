@@ -0,0 +1,100 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use clap::Parser;
+use fancy_talk_client::ServerAddress;
+
+const HELP : &str = "\
+Server addresses:
+  udp:HOST:PORT      UDP, one package per datagram, also used for plain HOST:PORT
+  tcp:HOST:PORT      TCP, packages preceded by their length
+  unix:PATH          Unix stream socket, framed like TCP
+  unixgram:PATH      Unix datagram socket
+
+Over datagram transports, unanswered queries are resent with the same ID,
+doubling the wait after each attempt so that all of them add up to --timeout.
+All queries are sent at once, each with its own ID, and the answers are
+matched to them by ID. Late answers to queries already answered are ignored.
+
+With --interactive, every line typed is sent as a query and answered right
+away. Lines starting with ':' are commands, type :help to list them.
+
+Exit codes:
+  0  all queries were answered
+  1  talking to the server failed
+  2  invalid arguments
+  3  the server did not answer in time
+  4  the server sent a malformed answer or rejected a query";
+
+/// Command-line client for the fancy-talk demo protocol
+#[derive(Debug, Parser)]
+#[command(version, about, after_help = HELP)]
+pub struct Args {
+    /// Queries to send, answered in order
+    #[arg(value_name = "QUERY", required_unless_present = "interactive")]
+    pub queries: Vec<String>,
+
+    /// Read queries from the terminal, with line editing and history
+    #[arg(short, long, conflicts_with_all = ["queries", "quiet"])]
+    pub interactive: bool,
+
+    /// Server to query, see below
+    #[arg(short, long, value_name = "ADDRESS", default_value = "udp:127.0.0.1:65432")]
+    pub server: ServerAddress,
+
+    /// Local address to send UDP queries from [default: any address, ephemeral port]
+    #[arg(short, long, value_name = "IP:PORT")]
+    pub bind: Option<SocketAddr>,
+
+    /// Seconds to wait for each answer, retransmissions included
+    #[arg(short, long, value_name = "SECONDS", default_value = "5", value_parser = parse_timeout)]
+    pub timeout: Duration,
+
+    /// How often to resend unanswered queries over datagram transports, fewer if they would come less than 100 ms apart
+    #[arg(short, long, value_name = "COUNT", default_value_t = 3)]
+    pub retries: u32,
+
+    /// Only report errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Show details of every query and answer
+    #[arg(short, long)]
+    pub verbose: bool,
+}
+
+fn parse_timeout(text: &str) -> Result<Duration, String> {
+    match text.parse::<f64>() {
+        Ok(seconds) if seconds > 0.0 && seconds.is_finite() => Ok(Duration::from_secs_f64(seconds)),
+        _ => Err(String::from("needs to be a positive number of seconds")),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_command() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn test_args() {
+        let args = Args::try_parse_from(["fancy-talk-client", "-s", "tcp:[::1]:7000", "-t", "0.5",
+                                         "greeting", "hamlet"]).unwrap();
+        assert_eq!(args.queries, ["greeting", "hamlet"]);
+        assert_eq!(args.server.to_string(), "tcp:[::1]:7000");
+        assert_eq!(args.timeout, Duration::from_millis(500));
+        assert_eq!(args.retries, 3);
+
+        assert!(Args::try_parse_from(["fancy-talk-client"]).is_err());
+        assert!(Args::try_parse_from(["fancy-talk-client", "-t", "0", "greeting"]).is_err());
+        assert!(Args::try_parse_from(["fancy-talk-client", "-q", "-v", "greeting"]).is_err());
+
+        assert!(Args::try_parse_from(["fancy-talk-client", "-i"]).unwrap().interactive);
+        assert!(Args::try_parse_from(["fancy-talk-client", "-i", "greeting"]).is_err());
+    }
+}
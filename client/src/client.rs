@@ -0,0 +1,614 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use fancy_talk::{Package, PROTOCOL_VERSION};
+
+use crate::address::ServerAddress;
+use crate::connection::Connection;
+use crate::errors::ClientError;
+
+/// How long to wait for answers, and how often to ask again over datagrams
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Retransmit {
+    /// Total time to wait for an answer, all attempts included
+    pub timeout: Duration,
+    /// Number of times an unanswered query is sent again
+    pub retries: u32,
+}
+
+impl Default for Retransmit {
+    fn default() -> Self {
+        Retransmit { timeout: Duration::from_secs(5), retries: 3 }
+    }
+}
+
+/// Shortest wait before the first resend, so many retries don't flood the server
+const MIN_FIRST_WAIT : Duration = Duration::from_millis(100);
+
+impl Retransmit {
+    /// How long to wait for an answer after attempt number `attempt`
+    ///
+    /// The wait doubles with every attempt and the waits add up to `timeout`.
+    /// Fewer attempts than asked for are made if the first wait would be
+    /// shorter than `MIN_FIRST_WAIT`, and never more than 16. Returns `None`
+    /// once all attempts are used up.
+    fn wait(&self, attempt: u32) -> Option<Duration> {
+        let mut attempts = self.retries.saturating_add(1).min(16);
+        while attempts > 1 && self.timeout / ((1u32 << attempts) - 1) < MIN_FIRST_WAIT {
+            attempts -= 1;
+        }
+        if attempt >= attempts {
+            return None;
+        }
+        let first = self.timeout / ((1u32 << attempts) - 1);
+        Some(first * (1 << attempt))
+    }
+}
+
+/// Build a query for `text` in the current protocol version
+pub fn new_query(text: &str) -> Package {
+    let mut query = Package::new().set_version(PROTOCOL_VERSION);
+    query.set_query(Some(String::from(text)));
+    query
+}
+
+/// Pass `answer` through, unless it is an error response
+pub fn accepted(answer: Package) -> Result<Package, ClientError> {
+    match answer.error_code() {
+        Some(code) => Err(ClientError::Rejected { code, reason: answer.payload.unwrap_or_default() }),
+        None => Ok(answer),
+    }
+}
+
+/// A query waiting for its answer
+struct Pending {
+    query: Package,
+    retransmit: Retransmit,
+    attempt: u32,
+    deadline: Instant,
+}
+
+/// Bookkeeping for queries in flight, shared by the blocking and async clients
+///
+/// Assigns IDs, decides when to send queries again and matches answers, the
+/// clients only move the packages.
+pub(crate) struct InFlight {
+    retransmit: Retransmit,
+    datagram: bool,
+    next_id: u16,
+    pending: HashMap<u16, Pending>,
+    answered: HashMap<u16, Package>,
+    timed_out: HashSet<u16>,
+}
+
+impl InFlight {
+    pub(crate) fn new(retransmit: Retransmit, datagram: bool) -> InFlight {
+        // Start somewhere unpredictable, so answers meant for an earlier run
+        // from the same port are unlikely to match
+        let next_id = RandomState::new().build_hasher().finish() as u16;
+        InFlight {
+            retransmit,
+            datagram,
+            next_id,
+            pending: HashMap::new(),
+            answered: HashMap::new(),
+            timed_out: HashSet::new(),
+        }
+    }
+
+    /// Queries that were sent and not yet collected, answered or not
+    pub(crate) fn len(&self) -> usize {
+        self.pending.len() + self.answered.len() + self.timed_out.len()
+    }
+
+    pub(crate) fn set_retransmit(&mut self, retransmit: Retransmit) {
+        self.retransmit = retransmit;
+    }
+
+    /// Give `query` a fresh ID and start its clock, returns the query to send
+    pub(crate) fn start(&mut self, query: Package) -> Result<Package, ClientError> {
+        if self.len() > u16::MAX as usize {
+            return Err(ClientError::Protocol(String::from("too many queries in flight")));
+        }
+        while self.is_pending(self.next_id) || self.answered.contains_key(&self.next_id)
+            || self.timed_out.contains(&self.next_id)
+        {
+            self.next_id = self.next_id.wrapping_add(1);
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let query = query.set_id(id);
+        let retransmit = self.retransmit;
+        let wait = self.wait(&retransmit, 0).unwrap_or_default();
+        let pending = Pending { query: query.clone(), retransmit, attempt: 0, deadline: Instant::now() + wait };
+        self.pending.insert(id, pending);
+        Ok(query)
+    }
+
+    /// Queries whose wait ran out at `now` and need to be sent again
+    ///
+    /// Queries that ran out of attempts are kept as timed out until they are
+    /// collected with `take_timed_out`.
+    pub(crate) fn expired(&mut self, now: Instant) -> Vec<Package> {
+        let expired: Vec<u16> = self.pending.iter()
+            .filter(|&(_, pending)| pending.deadline <= now)
+            .map(|(&id, _)| id)
+            .collect();
+
+        let mut resend = Vec::new();
+        for id in expired {
+            let pending = &self.pending[&id];
+            let attempt = pending.attempt + 1;
+            match self.wait(&pending.retransmit, attempt) {
+                Some(wait) => {
+                    let pending = self.pending.get_mut(&id).unwrap();
+                    pending.attempt = attempt;
+                    pending.deadline = now + wait;
+                    resend.push(pending.query.clone());
+                },
+                None => {
+                    self.pending.remove(&id);
+                    self.timed_out.insert(id);
+                },
+            }
+        }
+        resend
+    }
+
+    /// How long to wait from `now` for the next answer, `None` if nothing is pending
+    pub(crate) fn timeout(&self, now: Instant) -> Option<Duration> {
+        let deadline = self.pending.values().map(|pending| pending.deadline).min()?;
+        // Zero would mean no timeout at all to the socket
+        Some(deadline.saturating_duration_since(now).max(Duration::from_millis(1)))
+    }
+
+    /// Match `answer` to its query, dropping it if nothing is waiting for it
+    pub(crate) fn matched(&mut self, answer: Package) -> Option<(u16, Package)> {
+        self.pending.remove(&answer.id).map(|_| (answer.id, answer))
+    }
+
+    /// An answer kept while waiting for another one, for `id` or any query
+    pub(crate) fn take_answered(&mut self, id: Option<u16>) -> Option<(u16, Package)> {
+        let id = match id {
+            Some(id) => id,
+            None => *self.answered.keys().next()?,
+        };
+        self.answered.remove_entry(&id)
+    }
+
+    /// Keep an answer until it is collected
+    pub(crate) fn keep_answered(&mut self, (id, answer): (u16, Package)) {
+        self.answered.insert(id, answer);
+    }
+
+    /// A query that ran out of attempts, `id` or any of them
+    pub(crate) fn take_timed_out(&mut self, id: Option<u16>) -> Option<u16> {
+        let id = match id {
+            Some(id) => id,
+            None => *self.timed_out.iter().next()?,
+        };
+        self.timed_out.take(&id)
+    }
+
+    pub(crate) fn is_pending(&self, id: u16) -> bool {
+        self.pending.contains_key(&id)
+    }
+
+    pub(crate) fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// How long to wait after attempt number `attempt`, streams get one long wait
+    fn wait(&self, retransmit: &Retransmit, attempt: u32) -> Option<Duration> {
+        if self.datagram {
+            retransmit.wait(attempt)
+        } else if attempt == 0 {
+            Some(retransmit.timeout)
+        } else {
+            None
+        }
+    }
+}
+
+/// Sends queries to a server and matches the answers to them
+///
+/// Every query gets an ID that no other query in flight has, so several can
+/// be sent before the first answer arrives. Answers are matched by ID, those
+/// to queries that were already answered or given up on are dropped.
+pub struct FancyTalkClient {
+    connection: Connection,
+    in_flight: InFlight,
+}
+
+impl FancyTalkClient {
+    /// Connect to `server`
+    ///
+    /// UDP queries are sent from `bind`, or an ephemeral port if that is
+    /// `None`. Stream connections are given up on after `retransmit.timeout`.
+    pub fn open(server: &ServerAddress, bind: Option<SocketAddr>, retransmit: Retransmit) -> Result<FancyTalkClient, ClientError> {
+        let connection = Connection::open(server, bind, retransmit.timeout)?;
+        let in_flight = InFlight::new(retransmit, connection.is_datagram());
+        Ok(FancyTalkClient { connection, in_flight })
+    }
+
+    /// Connect to `server` with the default timeout and retries
+    pub fn connect(server: &ServerAddress) -> Result<FancyTalkClient, ClientError> {
+        FancyTalkClient::open(server, None, Retransmit::default())
+    }
+
+    /// Change the timeout and retries for queries sent from now on
+    pub fn set_retransmit(&mut self, retransmit: Retransmit) {
+        self.in_flight.set_retransmit(retransmit);
+    }
+
+    /// Number of queries sent but not yet collected with `receive` or `wait_for`
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Send `query` and return the ID it was given
+    pub fn send(&mut self, query: Package) -> Result<u16, ClientError> {
+        let query = self.in_flight.start(query)?;
+        self.connection.send(&query)?;
+        Ok(query.id)
+    }
+
+    /// Bytes of the last package sent to the server, as they went on the wire
+    ///
+    /// Stream transports include the length prefix.
+    pub fn last_sent(&self) -> &[u8] {
+        self.connection.last_sent()
+    }
+
+    /// Bytes of the last package that arrived from the server, as received
+    ///
+    /// Includes answers that failed to decode or were dropped as duplicates.
+    pub fn last_received(&self) -> &[u8] {
+        self.connection.last_received()
+    }
+
+    /// Wait for the answer to any query in flight
+    ///
+    /// Returns `None` if there is nothing left to wait for. A query that ran
+    /// out of attempts is reported as `ClientError::Timeout` with its ID.
+    pub fn receive(&mut self) -> Result<Option<(u16, Package)>, ClientError> {
+        loop {
+            if let Some(answer) = self.in_flight.take_answered(None) {
+                return Ok(Some(answer));
+            }
+            if let Some(id) = self.in_flight.take_timed_out(None) {
+                return Err(ClientError::Timeout { id });
+            }
+            if !self.in_flight.has_pending() {
+                return Ok(None);
+            }
+            if let Some(answer) = self.step()? {
+                return Ok(Some(answer));
+            }
+        }
+    }
+
+    /// Wait for the answer to the query with ID `id`
+    ///
+    /// Answers to other queries that arrive first are kept for later, and so
+    /// are timeouts of other queries.
+    pub fn wait_for(&mut self, id: u16) -> Result<Package, ClientError> {
+        loop {
+            if let Some((_, answer)) = self.in_flight.take_answered(Some(id)) {
+                return Ok(answer);
+            }
+            if self.in_flight.take_timed_out(Some(id)).is_some() {
+                return Err(ClientError::Timeout { id });
+            }
+            if !self.in_flight.is_pending(id) {
+                return Err(ClientError::Protocol(format!("no query with ID {} is in flight", id)));
+            }
+            if let Some(answer) = self.step()? {
+                self.in_flight.keep_answered(answer);
+            }
+        }
+    }
+
+    /// Resend what is due, then wait for an answer until the next query is due
+    fn step(&mut self) -> Result<Option<(u16, Package)>, ClientError> {
+        let now = Instant::now();
+        for query in self.in_flight.expired(now) {
+            self.connection.send(&query)?;
+        }
+        let timeout = match self.in_flight.timeout(now) {
+            Some(timeout) => timeout,
+            None => return Ok(None),
+        };
+        Ok(self.connection.receive(timeout)?.and_then(|answer| self.in_flight.matched(answer)))
+    }
+
+    /// Ask the server about `text` and wait for the answer
+    ///
+    /// Error responses are returned as `ClientError::Rejected`.
+    pub fn query(&mut self, text: &str) -> Result<Package, ClientError> {
+        let id = self.send(new_query(text))?;
+        accepted(self.wait_for(id)?)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::UdpSocket;
+    use std::os::unix::net::{UnixDatagram, UnixListener};
+    use std::process;
+    use std::thread;
+
+    use fancy_talk::{Decoder, Encoder, Serialisable};
+
+    fn decode(datagram: &[u8]) -> Package {
+        Package::read(&mut Decoder::new(datagram)).unwrap()
+    }
+
+    fn encode(package: &Package) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+        package.write(&mut Encoder::new(&mut buffer)).unwrap();
+        buffer
+    }
+
+    /// Echo every query back as its own answer, rejecting "bad"
+    fn echo(query: Package) -> Package {
+        let payload = query.query.clone();
+        match payload.as_deref() {
+            Some("bad") => Package::error_response(query.id, &fancy_talk::Error::FrameTooLong { len: 0 }),
+            _ => query.set_payload(payload),
+        }
+    }
+
+    #[test]
+    fn test_waits() {
+        let waits = |retransmit: Retransmit| -> Vec<Duration> {
+            (0..).map_while(|attempt| retransmit.wait(attempt)).collect()
+        };
+        let retransmit = Retransmit { timeout: Duration::from_millis(1500), retries: 3 };
+        let millis: Vec<u64> = waits(retransmit).iter().map(|wait| wait.as_millis() as u64).collect();
+        assert_eq!(millis, [100, 200, 400, 800]);
+
+        let once = Retransmit { timeout: Duration::from_secs(2), retries: 0 };
+        assert_eq!(waits(once), [Duration::from_secs(2)]);
+
+        // Retries that don't fit into the timeout are dropped
+        let many = Retransmit { timeout: Duration::from_secs(5), retries: 15 };
+        let millis: Vec<u64> = waits(many).iter().map(|wait| wait.as_millis() as u64).collect();
+        assert_eq!(millis, [161, 322, 645, 1290, 2580]);
+
+        let many = Retransmit { timeout: Duration::from_secs(2), retries: u32::MAX };
+        assert_eq!(waits(many).len(), 4);
+        assert!(waits(many)[0] >= MIN_FIRST_WAIT);
+
+        let short = Retransmit { timeout: Duration::from_millis(50), retries: 3 };
+        assert_eq!(waits(short), [Duration::from_millis(50)]);
+
+        let long = Retransmit { timeout: Duration::from_secs(7200), retries: u32::MAX };
+        assert_eq!(waits(long).len(), 16);
+    }
+
+    #[test]
+    fn test_expired() {
+        let retransmit = Retransmit { timeout: Duration::from_millis(300), retries: 1 };
+        let mut in_flight = InFlight::new(retransmit, true);
+        let first = in_flight.start(Package::new()).unwrap().id;
+        let second = in_flight.start(Package::new()).unwrap().id;
+        // The first query is on its last attempt, the second one on its first
+        in_flight.pending.get_mut(&first).unwrap().attempt = 1;
+
+        let later = Instant::now() + Duration::from_secs(1);
+        let resend = in_flight.expired(later);
+        assert_eq!(resend.iter().map(|query| query.id).collect::<Vec<_>>(), [second]);
+        assert!(in_flight.is_pending(second));
+        assert!(!in_flight.is_pending(first));
+        assert_eq!(in_flight.pending[&second].attempt, 1);
+        assert_eq!(in_flight.len(), 2);
+        assert_eq!(in_flight.take_timed_out(None), Some(first));
+        assert_eq!(in_flight.take_timed_out(None), None);
+    }
+
+    #[test]
+    fn test_retransmit() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = ServerAddress::Udp(server.local_addr().unwrap().to_string());
+
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            // Drop the first attempt, answer the second one late and twice
+            let (_, client) = server.recv_from(&mut buf).unwrap();
+            let (amt, _) = server.recv_from(&mut buf).unwrap();
+            let query = decode(&buf[..amt]);
+            let stale = query.clone().set_id(query.id.wrapping_sub(1)).set_payload(Some(String::from("stale")));
+            let answer = query.set_payload(Some(String::from("fresh")));
+            for response in &[stale, answer.clone(), answer] {
+                server.send_to(&encode(response), client).unwrap();
+            }
+            server
+        });
+
+        let retransmit = Retransmit { timeout: Duration::from_secs(3), retries: 2 };
+        let mut client = FancyTalkClient::open(&address, None, retransmit).unwrap();
+        assert_eq!(client.query("greeting").unwrap().payload.as_deref(), Some("fresh"));
+        let _server = handle.join().unwrap();
+        assert_eq!(client.in_flight(), 0);
+
+        // The duplicate answer to the last query is not taken for this one
+        client.set_retransmit(Retransmit { timeout: Duration::from_millis(100), retries: 1 });
+        match client.query("greeting") {
+            Err(ClientError::Timeout { .. }) => (),
+            other => panic!("unexpected {:?}", other.map(|answer| answer.payload)),
+        }
+        assert_eq!(client.in_flight(), 0);
+        assert!(client.receive().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_pipelined_timeout() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = ServerAddress::Udp(server.local_addr().unwrap().to_string());
+
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            let (_, _) = server.recv_from(&mut buf).unwrap();
+            let (amt, client) = server.recv_from(&mut buf).unwrap();
+            // Only answer the second query, after the first one gave up
+            thread::sleep(Duration::from_millis(300));
+            server.send_to(&encode(&echo(decode(&buf[..amt]))), client).unwrap();
+            server
+        });
+
+        let short = Retransmit { timeout: Duration::from_millis(100), retries: 0 };
+        let mut client = FancyTalkClient::open(&address, None, short).unwrap();
+        let unanswered = client.send(new_query("one")).unwrap();
+        client.set_retransmit(Retransmit { timeout: Duration::from_secs(3), retries: 0 });
+        let answered = client.send(new_query("two")).unwrap();
+
+        assert_eq!(client.wait_for(answered).unwrap().payload.as_deref(), Some("two"));
+        assert_eq!(client.in_flight(), 1);
+        match client.wait_for(unanswered) {
+            Err(e @ ClientError::Timeout { .. }) => {
+                assert_eq!(e.exit_code(), 3);
+                assert!(matches!(e, ClientError::Timeout { id } if id == unanswered));
+            },
+            other => panic!("unexpected {:?}", other.map(|answer| answer.payload)),
+        }
+        assert_eq!(client.in_flight(), 0);
+        let _server = handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_pipelining() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = ServerAddress::Udp(server.local_addr().unwrap().to_string());
+        let texts = ["one", "two", "bad", "three"];
+
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            let mut queries = Vec::new();
+            for _ in 0..texts.len() {
+                let (amt, client) = server.recv_from(&mut buf).unwrap();
+                queries.push((decode(&buf[..amt]), client));
+            }
+            // Answer in reverse order
+            for (query, client) in queries.into_iter().rev() {
+                server.send_to(&encode(&echo(query)), client).unwrap();
+            }
+            server
+        });
+
+        let retransmit = Retransmit { timeout: Duration::from_secs(3), retries: 0 };
+        let mut client = FancyTalkClient::open(&address, None, retransmit).unwrap();
+        let ids: Vec<u16> = texts.iter().map(|text| client.send(new_query(text)).unwrap()).collect();
+        assert_eq!(client.in_flight(), 4);
+        for (i, id) in ids.iter().enumerate() {
+            assert!(!ids[..i].contains(id));
+        }
+
+        for (&id, text) in ids.iter().zip(&texts) {
+            let answer = client.wait_for(id).unwrap();
+            assert_eq!(answer.id, id);
+            match accepted(answer) {
+                Ok(answer) => assert_eq!(answer.payload.as_deref(), Some(*text)),
+                Err(ClientError::Rejected { code, .. }) => assert_eq!((*text, code), ("bad", 5)),
+                Err(e) => panic!("unexpected {}", e),
+            }
+        }
+        assert_eq!(client.in_flight(), 0);
+        let _server = handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_large_datagram() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = ServerAddress::Udp(server.local_addr().unwrap().to_string());
+
+        let handle = thread::spawn(move || {
+            let mut buf = vec![0u8; fancy_talk::MAX_DATAGRAM_LEN];
+            let (amt, client) = server.recv_from(&mut buf).unwrap();
+            server.send_to(&encode(&echo(decode(&buf[..amt]))), client).unwrap();
+        });
+
+        // Query and answer are both well over 4096 bytes
+        let text = "x".repeat(10000);
+        let mut client = FancyTalkClient::connect(&address).unwrap();
+        assert_eq!(client.query(&text).unwrap().payload, Some(text));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_raw_bytes() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = ServerAddress::Udp(server.local_addr().unwrap().to_string());
+
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            let (amt, client) = server.recv_from(&mut buf).unwrap();
+            // Just the ID and flags
+            server.send_to(&buf[..3], client).unwrap();
+            buf[..amt].to_vec()
+        });
+
+        let mut client = FancyTalkClient::connect(&address).unwrap();
+        match client.query("greeting") {
+            Err(ClientError::Protocol(_)) => (),
+            other => panic!("unexpected {:?}", other.map(|answer| answer.payload)),
+        }
+        let sent = handle.join().unwrap();
+        assert_eq!(client.last_sent(), &sent[..]);
+        assert_eq!(client.last_received(), &sent[..3]);
+    }
+
+    #[test]
+    fn test_unix() {
+        let stream_path = env::temp_dir().join(format!("fancy-talk-client-test-{}-stream.sock", process::id()));
+        let datagram_path = env::temp_dir().join(format!("fancy-talk-client-test-{}-datagram.sock", process::id()));
+        let _ = fs::remove_file(&stream_path);
+        let _ = fs::remove_file(&datagram_path);
+        let listener = UnixListener::bind(&stream_path).unwrap();
+        let server = UnixDatagram::bind(&datagram_path).unwrap();
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut len = [0u8; 4];
+            stream.read_exact(&mut len).unwrap();
+            let mut buf = vec![0u8; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut buf).unwrap();
+            let answer = encode(&echo(decode(&buf)));
+            stream.write_all(&(answer.len() as u32).to_be_bytes()).unwrap();
+            stream.write_all(&answer).unwrap();
+
+            // Only possible if the client bound its socket to a path
+            let mut buf = [0u8; 4096];
+            let (amt, client) = server.recv_from(&mut buf).unwrap();
+            let reply = client.as_pathname().unwrap().to_path_buf();
+            server.send_to(&encode(&echo(decode(&buf[..amt]))), &reply).unwrap();
+            reply
+        });
+
+        let mut client = FancyTalkClient::connect(&ServerAddress::Unix(stream_path.clone())).unwrap();
+        assert_eq!(client.query("stream").unwrap().payload.as_deref(), Some("stream"));
+        // Raw bytes on streams come with their length prefix
+        for raw in [client.last_sent(), client.last_received()] {
+            assert_eq!(&raw[..4], &(raw.len() as u32 - 4).to_be_bytes());
+        }
+        assert_eq!(decode(&client.last_received()[4..]).payload.as_deref(), Some("stream"));
+        let mut client = FancyTalkClient::connect(&ServerAddress::UnixDatagram(datagram_path.clone())).unwrap();
+        assert_eq!(client.query("datagram").unwrap().payload.as_deref(), Some("datagram"));
+
+        let reply = handle.join().unwrap();
+        assert!(reply.exists());
+        drop(client);
+        assert!(!reply.exists(), "{} was left behind", reply.display());
+        fs::remove_file(&stream_path).unwrap();
+        fs::remove_file(&datagram_path).unwrap();
+    }
+}
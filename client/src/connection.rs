@@ -0,0 +1,238 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use fancy_talk::{Package, Encoder, Decoder, Serialisable, StreamDecoder, Decoded, Framing, MAX_DATAGRAM_LEN};
+
+use crate::address::ServerAddress;
+use crate::errors::ClientError;
+
+/// How much to read from a stream at a time
+const READ_SIZE : usize = 4096;
+
+pub(crate) fn encode(query: &Package, framing: Framing) -> Result<Vec<u8>, ClientError> {
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut encoder = Encoder::new(&mut buffer);
+    let encoded = match framing {
+        Framing::Bare => query.write(&mut encoder),
+        Framing::LengthPrefixed => query.write_framed(&mut encoder),
+    };
+    encoded.map_err(|e| ClientError::Protocol(format!("encoding the query failed: {}", e)))?;
+    Ok(buffer)
+}
+
+pub(crate) fn decode(datagram: &[u8]) -> Result<Package, ClientError> {
+    Package::read(&mut Decoder::new(datagram))
+        .map_err(|e| ClientError::Protocol(format!("malformed answer: {}", e)))
+}
+
+/// Datagram sockets connected to the server
+trait Datagram {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Datagram for UdpSocket {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        UdpSocket::recv(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+}
+
+impl Datagram for UnixDatagram {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        UnixDatagram::recv(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixDatagram::set_read_timeout(self, timeout)
+    }
+}
+
+/// Resolve `address` to the first socket address it names
+pub(crate) fn resolve(address: &str) -> Result<SocketAddr, ClientError> {
+    address.to_socket_addrs()?.next().ok_or_else(|| {
+        ClientError::Network(io::Error::new(io::ErrorKind::NotFound, format!("{} does not resolve", address)))
+    })
+}
+
+/// Connected transport to a server
+enum Transport {
+    Udp(UdpSocket),
+    UnixDatagram(BoundDatagram<UnixDatagram>),
+    Tcp(TcpStream, StreamDecoder),
+    Unix(UnixStream, StreamDecoder),
+}
+
+/// A connection to a server, over any of the transports
+///
+/// Stream transports keep one connection open for all queries. Connections
+/// only move packages, see `FancyTalkClient` for matching answers to queries.
+pub(crate) struct Connection {
+    transport: Transport,
+    /// Bytes of the last package sent, as they went on the wire
+    sent: Vec<u8>,
+    /// Bytes of the last package received, whether it decoded or not
+    received: Vec<u8>,
+}
+
+impl Connection {
+    /// Connect to `server`, giving up after `timeout`
+    ///
+    /// UDP queries are sent from `bind`, or an ephemeral port if that is
+    /// `None`.
+    pub fn open(server: &ServerAddress, bind: Option<SocketAddr>, timeout: Duration) -> Result<Connection, ClientError> {
+        let stream_decoder = || StreamDecoder::new().set_framing(Framing::LengthPrefixed);
+
+        let transport = match *server {
+            ServerAddress::Udp(ref address) => {
+                let address = resolve(address)?;
+                let local = bind.unwrap_or_else(|| match address {
+                    SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+                    SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+                });
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(address)?;
+                Transport::Udp(socket)
+            },
+            ServerAddress::Tcp(ref address) => {
+                let stream = TcpStream::connect_timeout(&resolve(address)?, timeout)?;
+                Transport::Tcp(stream, stream_decoder())
+            },
+            ServerAddress::Unix(ref path) => Transport::Unix(UnixStream::connect(path)?, stream_decoder()),
+            ServerAddress::UnixDatagram(ref path) => {
+                let bound = BoundDatagram::bind(|path| UnixDatagram::bind(path))?;
+                bound.socket.connect(path)?;
+                Transport::UnixDatagram(bound)
+            },
+        };
+        Ok(Connection { transport, sent: Vec::new(), received: Vec::new() })
+    }
+
+    /// Whether packages can get lost on the way and need to be sent again
+    pub fn is_datagram(&self) -> bool {
+        match self.transport {
+            Transport::Udp(_) | Transport::UnixDatagram(_) => true,
+            Transport::Tcp(..) | Transport::Unix(..) => false,
+        }
+    }
+
+    /// Send `package` to the server
+    pub fn send(&mut self, package: &Package) -> Result<(), ClientError> {
+        let framing = if self.is_datagram() { Framing::Bare } else { Framing::LengthPrefixed };
+        self.sent = encode(package, framing)?;
+        match self.transport {
+            Transport::Udp(ref socket) => { socket.send(&self.sent)?; },
+            Transport::UnixDatagram(ref bound) => { bound.socket.send(&self.sent)?; },
+            Transport::Tcp(ref mut stream, _) => stream.write_all(&self.sent)?,
+            Transport::Unix(ref mut stream, _) => stream.write_all(&self.sent)?,
+        }
+        Ok(())
+    }
+
+    /// Bytes of the last package sent, length prefix included on streams
+    pub fn last_sent(&self) -> &[u8] {
+        &self.sent
+    }
+
+    /// Bytes of the last package received, including one that failed to decode
+    pub fn last_received(&self) -> &[u8] {
+        &self.received
+    }
+
+    /// Wait up to `timeout` for the next package from the server
+    ///
+    /// Returns `None` if nothing arrived in time.
+    pub fn receive(&mut self, timeout: Duration) -> Result<Option<Package>, ClientError> {
+        let received = match self.transport {
+            Transport::Udp(ref socket) => receive_datagram(socket, timeout, &mut self.received),
+            Transport::UnixDatagram(ref bound) => receive_datagram(&bound.socket, timeout, &mut self.received),
+            Transport::Tcp(ref mut stream, ref mut decoder) => {
+                stream.set_read_timeout(Some(timeout))?;
+                receive_stream(stream, decoder, &mut self.received)
+            },
+            Transport::Unix(ref mut stream, ref mut decoder) => {
+                stream.set_read_timeout(Some(timeout))?;
+                receive_stream(stream, decoder, &mut self.received)
+            },
+        };
+        match received {
+            Ok(package) => Ok(Some(package)),
+            // Read timeouts show up as either, depending on the platform
+            Err(ClientError::Network(ref e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                Ok(None)
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Unix datagram socket bound to a temporary path, so the server can answer
+///
+/// The socket file is removed again when dropped.
+pub(crate) struct BoundDatagram<S> {
+    pub(crate) socket: S,
+    path: PathBuf,
+}
+
+impl<S> BoundDatagram<S> {
+    /// Bind a new socket with `bind` to a path no other client uses
+    pub(crate) fn bind<F>(bind: F) -> io::Result<BoundDatagram<S>>
+        where F: FnOnce(&Path) -> io::Result<S>
+    {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("fancy-talk-client-{}-{}.sock", process::id(), COUNT.fetch_add(1, Ordering::Relaxed));
+        let path = env::temp_dir().join(name);
+        let _ = fs::remove_file(&path);
+        let socket = bind(&path)?;
+        Ok(BoundDatagram { socket, path })
+    }
+}
+
+impl<S> Drop for BoundDatagram<S> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Receive and decode the next datagram, keeping its bytes in `received`
+fn receive_datagram<S: Datagram>(socket: &S, timeout: Duration, received: &mut Vec<u8>) -> Result<Package, ClientError> {
+    let mut in_buf = vec![0u8; MAX_DATAGRAM_LEN];
+    socket.set_read_timeout(Some(timeout))?;
+    let amt = socket.recv(&mut in_buf)?;
+    in_buf.truncate(amt);
+    *received = in_buf;
+    decode(received)
+}
+
+/// Read until the next package is complete, keeping its frame in `received`
+fn receive_stream<S: Read>(stream: &mut S, decoder: &mut StreamDecoder, received: &mut Vec<u8>) -> Result<Package, ClientError> {
+    let mut in_buf = [0u8; READ_SIZE];
+    loop {
+        let decoded = decoder.decode();
+        if !decoder.last_frame().is_empty() {
+            *received = decoder.last_frame().to_vec();
+        }
+        match decoded {
+            Ok(Decoded::Package(package)) => return Ok(package),
+            Ok(Decoded::NeedMore(_)) => {
+                let amt = stream.read(&mut in_buf)?;
+                if amt == 0 {
+                    let closed = io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection");
+                    return Err(ClientError::Network(closed));
+                }
+                decoder.feed(&in_buf[..amt]);
+            },
+            Err(e) => return Err(ClientError::Protocol(format!("malformed answer: {}", e))),
+        }
+    }
+}
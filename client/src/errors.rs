@@ -0,0 +1,58 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Ways a query can fail, each with its own exit code
+#[derive(Debug)]
+pub enum ClientError {
+    /// Talking to the server failed
+    Network(io::Error),
+    /// The server did not answer the query with this ID in time
+    Timeout {
+        id: u16,
+    },
+    /// The server sent something we could not make sense of
+    Protocol(String),
+    /// The server answered with an error response
+    Rejected {
+        code: u8,
+        reason: String,
+    },
+}
+
+impl ClientError {
+    /// Exit code for the command-line client, 2 is taken by invalid arguments
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            ClientError::Network(_) => 1,
+            ClientError::Timeout { .. } => 3,
+            ClientError::Protocol(_) | ClientError::Rejected { .. } => 4,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientError::Network(ref e) => write!(f, "network error: {}", e),
+            ClientError::Timeout { id } => write!(f, "timed out waiting for the answer to query {}", id),
+            ClientError::Protocol(ref message) => write!(f, "protocol error: {}", message),
+            ClientError::Rejected { code, ref reason } => write!(f, "server rejected the query: {} (error {})", reason, code),
+        }
+    }
+}
+
+impl error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ClientError::Network(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> ClientError {
+        ClientError::Network(err)
+    }
+}
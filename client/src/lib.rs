@@ -0,0 +1,30 @@
+//! Client library for the fancy-talk demo protocol
+//!
+//! `FancyTalkClient` sets up the socket for any of the transports, gives
+//! every query its own ID, resends unanswered queries over datagram
+//! transports and matches the answers to their queries:
+//!
+//! ```no_run
+//! use fancy_talk_client::{FancyTalkClient, ServerAddress};
+//!
+//! let server: ServerAddress = "udp:127.0.0.1:65432".parse().unwrap();
+//! let mut client = FancyTalkClient::connect(&server).unwrap();
+//! let answer = client.query("greeting").unwrap();
+//! println!("{}", answer.payload.unwrap_or_default());
+//! ```
+//!
+//! With the `tokio` feature, `AsyncFancyTalkClient` does the same on a
+//! tokio runtime.
+
+mod address;
+mod client;
+mod connection;
+mod errors;
+#[cfg(feature = "tokio")]
+mod tokio_client;
+
+pub use address::ServerAddress;
+pub use client::{accepted, new_query, FancyTalkClient, Retransmit};
+pub use errors::ClientError;
+#[cfg(feature = "tokio")]
+pub use tokio_client::AsyncFancyTalkClient;
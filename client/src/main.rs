@@ -1,87 +1,80 @@
-extern crate fancy_talk;
-extern crate ansi_term;
-use std::env;
-use std::net::UdpSocket;
 use std::process;
-use std::str;
 
-use fancy_talk::{Package, Encoder, Decoder, Serialisable};
 use ansi_term::Color::RGB;
+use ansi_term::Style as AnsiStyle;
+use clap::Parser;
+use fancy_talk::{Package, Style};
+use fancy_talk_client::{accepted, new_query, ClientError, FancyTalkClient, Retransmit};
 
-const MAX_UDP_SIZE : usize = 4096;
+use crate::cli::Args;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-
-    let config = Config::new(&args).unwrap_or_else(|err| {
-        println!("Problem parsing arguments: {}", err);
-        process::exit(2);
-    });
-
-    let socket = UdpSocket::bind("127.0.0.1:65433").unwrap();
-
-    let mut query = Package::new();
-    query.set_query(Some(config.query));
+mod cli;
+mod repl;
 
+fn main() {
+    let args = Args::parse();
 
-    let mut out_buf: Vec<u8> = Vec::new();
-
-    {
-        let mut encoder = Encoder::new(&mut out_buf);
-        query.write(&mut encoder).expect("Failed to encode query");
+    let result = if args.interactive { repl::run(&args) } else { run(&args) };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(e.exit_code());
     }
+}
 
-    let sent_size = socket.send_to(out_buf.as_slice(), (config.address.as_str(), config.port)).expect("Failed to send data to server");
-    println!("Sent {} bytes", sent_size);
-    let mut in_buf : [u8; MAX_UDP_SIZE] = [0; MAX_UDP_SIZE];
-    let amt = socket.recv(&mut in_buf).expect("Reading from server failed");
-    println!("Got {} bytes.", amt);
-
-    let mut decoder = Decoder::new(&in_buf);
-
-    let response = Package::read(&mut decoder).expect("Parsing the response failed");
-
-    let mut outstyle = RGB(response.red, response.green, response.blue).normal();
-    if response.bold {
-        outstyle = outstyle.bold();
-    }
-    if response.italic {
-        outstyle = outstyle.italic();
-    }
-    if response.underlined {
-        outstyle = outstyle.underline();
-    }
-    if response.blink {
-        outstyle = outstyle.blink();
+/// Send all queries at once, then print the answers in order
+///
+/// Stops at the first query that fails.
+fn run(args: &Args) -> Result<(), ClientError> {
+    let retransmit = Retransmit { timeout: args.timeout, retries: args.retries };
+    let mut client = FancyTalkClient::open(&args.server, args.bind, retransmit)?;
+    if args.verbose {
+        eprintln!("Connected to {}", args.server);
     }
 
-    let res_text = match response.payload {
-        None => String::from("<empty>"),
-        Some(text) => text,
-    };
+    let mut ids = Vec::with_capacity(args.queries.len());
+    for text in &args.queries {
+        let query = new_query(text);
+        let len = query.encoded_len();
+        let id = client.send(query)?;
+        if args.verbose {
+            eprintln!("Sending query {:?} with ID {}, {} bytes", text, id, len);
+        }
+        ids.push(id);
+    }
 
-    println!("{}", outstyle.paint(res_text));
+    for &id in &ids {
+        let response = client.wait_for(id)?;
+        if args.verbose {
+            eprintln!("{}", describe(&response));
+        }
 
+        let response = accepted(response)?;
+        if !args.quiet {
+            let text = response.payload.as_deref().unwrap_or("<empty>");
+            println!("{}", style_of(&response).paint(text));
+        }
+    }
+    Ok(())
 }
 
-struct Config {
-    address: String,
-    port: u16,
-    query: String,
+/// Details of `response` for verbose output
+fn describe(response: &Package) -> String {
+    format!("Got answer with ID {}, {} bytes, protocol version {}, style {}, colour #{:02x}{:02x}{:02x}",
+            response.id, response.encoded_len(), response.version().unwrap_or(0),
+            response.style, response.red, response.green, response.blue)
 }
 
-impl Config {
-    fn new(args: &[String]) -> Result<Config, &'static str> {
-
-        if args.len() < 4 {
-            return Err("not enough arguments")
-        }
-
-        let address = args[1].clone();
-        let port = args[2].parse::<u16>().unwrap();
-        let query = args[3].clone();
-
-        Ok(Config { address, port, query })
+/// Terminal style matching the colour and style flags of `response`
+fn style_of(response: &Package) -> AnsiStyle {
+    let mut outstyle = RGB(response.red, response.green, response.blue).normal();
+    for flag in response.style {
+        outstyle = match flag {
+            Style::BOLD => outstyle.bold(),
+            Style::ITALIC => outstyle.italic(),
+            Style::UNDERLINED => outstyle.underline(),
+            Style::BLINK => outstyle.blink(),
+            _ => outstyle,
+        };
     }
-
+    outstyle
 }
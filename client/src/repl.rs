@@ -0,0 +1,232 @@
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use fancy_talk_client::{accepted, new_query, ClientError, FancyTalkClient, Retransmit, ServerAddress};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use crate::cli::Args;
+use crate::{describe, style_of};
+
+const PROMPT : &str = "fancy-talk> ";
+
+const COMMANDS : &str = "\
+Commands:
+  :server [ADDRESS]  show the server, or switch to another one
+  :hex               toggle hex dumps of queries and answers
+  :latency           toggle showing the round-trip time of each query
+  :help              show this list
+  :quit              leave, as does Ctrl-D
+Anything else is sent as a query.";
+
+/// A line typed at the prompt
+#[derive(Debug, PartialEq)]
+enum Command<'a> {
+    Query(&'a str),
+    Server(Option<&'a str>),
+    Hex,
+    Latency,
+    Help,
+    Quit,
+    Unknown(&'a str),
+}
+
+impl<'a> Command<'a> {
+    /// Parse `line`, `None` if there is nothing to do
+    fn parse(line: &'a str) -> Option<Command<'a>> {
+        let line = line.trim();
+        if line.is_empty() {
+            return None;
+        }
+        let command = match line.strip_prefix(':') {
+            Some(command) => command,
+            None => return Some(Command::Query(line)),
+        };
+
+        let mut words = command.split_whitespace();
+        let command = match (words.next(), words.next(), words.next()) {
+            (Some("server"), server, None) => Command::Server(server),
+            (Some("hex"), None, None) => Command::Hex,
+            (Some("latency"), None, None) => Command::Latency,
+            (Some("help"), None, None) => Command::Help,
+            (Some("quit"), None, None) => Command::Quit,
+            _ => Command::Unknown(line),
+        };
+        Some(command)
+    }
+}
+
+/// Connection and display settings, changed by the commands
+struct Session<'a> {
+    args: &'a Args,
+    server: ServerAddress,
+    client: FancyTalkClient,
+    hex: bool,
+    latency: bool,
+}
+
+impl<'a> Session<'a> {
+    fn run(&mut self, command: Command) {
+        match command {
+            Command::Query(text) => {
+                if let Err(e) = self.query(text) {
+                    eprintln!("{}", e);
+                }
+            },
+            Command::Server(None) => println!("Connected to {}", self.server),
+            Command::Server(Some(address)) => self.switch(address),
+            Command::Hex => {
+                self.hex = !self.hex;
+                println!("Hex dumps {}", if self.hex { "on" } else { "off" });
+            },
+            Command::Latency => {
+                self.latency = !self.latency;
+                println!("Latency {}", if self.latency { "on" } else { "off" });
+            },
+            Command::Help => println!("{}", COMMANDS),
+            Command::Quit => (),
+            Command::Unknown(line) => eprintln!("unknown command '{}', type :help to list them", line),
+        }
+    }
+
+    /// Send `text` as a query and print the answer
+    fn query(&mut self, text: &str) -> Result<(), ClientError> {
+        let query = new_query(text);
+        let start = Instant::now();
+        let id = self.client.send(query.clone())?;
+        if self.args.verbose {
+            eprintln!("Sending query {:?} with ID {}, {} bytes", text, id, query.encoded_len());
+        }
+        if self.hex {
+            println!("{}", hex_dump("> ", self.client.last_sent()));
+        }
+
+        let response = self.client.wait_for(id);
+        let elapsed = start.elapsed();
+        // Malformed answers are dumped too, that's when the bytes matter most
+        if self.hex && matches!(response, Ok(_) | Err(ClientError::Protocol(_))) {
+            println!("{}", hex_dump("< ", self.client.last_received()));
+        }
+        let response = response?;
+        if self.args.verbose {
+            eprintln!("{}", describe(&response));
+        }
+
+        let response = accepted(response)?;
+        let text = response.payload.as_deref().unwrap_or("<empty>");
+        println!("{}", style_of(&response).paint(text));
+        if self.latency {
+            println!("Answered in {:.1} ms", elapsed.as_secs_f64() * 1000.0);
+        }
+        Ok(())
+    }
+
+    /// Connect to `address` instead, staying with the old server if that fails
+    fn switch(&mut self, address: &str) {
+        let server = match address.parse::<ServerAddress>() {
+            Ok(server) => server,
+            Err(e) => return eprintln!("{}", e),
+        };
+        match FancyTalkClient::open(&server, self.args.bind, retransmit(self.args)) {
+            Ok(client) => {
+                println!("Connected to {}", server);
+                self.client = client;
+                self.server = server;
+            },
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+fn retransmit(args: &Args) -> Retransmit {
+    Retransmit { timeout: args.timeout, retries: args.retries }
+}
+
+/// `bytes` as rows of 16, each starting with `prefix` and the offset
+fn hex_dump(prefix: &str, bytes: &[u8]) -> String {
+    let rows: Vec<String> = bytes.chunks(16).enumerate().map(|(row, chunk)| {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{}{:04x}  {}", prefix, row * 16, hex.join(" "))
+    }).collect();
+    rows.join("\n")
+}
+
+/// Where to keep the history between sessions, if there is a home directory
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".fancy_talk_history"))
+}
+
+fn terminal_error(err: ReadlineError) -> ClientError {
+    match err {
+        ReadlineError::Io(e) => ClientError::Network(e),
+        e => ClientError::Network(io::Error::other(e.to_string())),
+    }
+}
+
+/// Send every line typed as a query until the user quits
+///
+/// Failed queries are reported and the session goes on, only failing to
+/// connect at the start or to read from the terminal ends it.
+pub fn run(args: &Args) -> Result<(), ClientError> {
+    let client = FancyTalkClient::open(&args.server, args.bind, retransmit(args))?;
+    let mut editor = DefaultEditor::new().map_err(terminal_error)?;
+    let history = history_path();
+    if let Some(ref path) = history {
+        let _ = editor.load_history(path);
+    }
+
+    println!("Connected to {}, type :help for the commands", args.server);
+    let mut session = Session { args, server: args.server.clone(), client, hex: false, latency: false };
+    loop {
+        let line = match editor.readline(PROMPT) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(terminal_error(e)),
+        };
+        let command = match Command::parse(&line) {
+            Some(command) => command,
+            None => continue,
+        };
+        let _ = editor.add_history_entry(line.as_str());
+        if command == Command::Quit {
+            break;
+        }
+        session.run(command);
+    }
+
+    if let Some(ref path) = history {
+        let _ = editor.save_history(path);
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Command::parse("  "), None);
+        assert_eq!(Command::parse(" hamlet "), Some(Command::Query("hamlet")));
+        assert_eq!(Command::parse(":server"), Some(Command::Server(None)));
+        assert_eq!(Command::parse(":server tcp:[::1]:7000"), Some(Command::Server(Some("tcp:[::1]:7000"))));
+        assert_eq!(Command::parse(":hex"), Some(Command::Hex));
+        assert_eq!(Command::parse(":latency"), Some(Command::Latency));
+        assert_eq!(Command::parse(":quit"), Some(Command::Quit));
+        assert_eq!(Command::parse(":hex on"), Some(Command::Unknown(":hex on")));
+        assert_eq!(Command::parse(":"), Some(Command::Unknown(":")));
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        assert_eq!(hex_dump("> ", &[]), "");
+        assert_eq!(hex_dump("> ", &[0x23, 0x42, 0x00]), "> 0000  23 42 00");
+        let bytes: Vec<u8> = (0..18).collect();
+        assert_eq!(hex_dump("< ", &bytes),
+                   "< 0000  00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n< 0010  10 11");
+    }
+}
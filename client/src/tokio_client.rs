@@ -0,0 +1,249 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use fancy_talk::{CodecError, Framing, Package, PackageCodec, MAX_DATAGRAM_LEN};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{lookup_host, TcpStream, UdpSocket, UnixDatagram, UnixStream};
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+
+use crate::address::ServerAddress;
+use crate::client::{accepted, new_query, InFlight, Retransmit};
+use crate::connection::{decode, encode, BoundDatagram};
+use crate::errors::ClientError;
+
+/// Connected transport to a server
+enum Transport {
+    Udp(UdpSocket),
+    UnixDatagram(BoundDatagram<UnixDatagram>),
+    Tcp(Framed<TcpStream, PackageCodec>),
+    Unix(Framed<UnixStream, PackageCodec>),
+}
+
+impl From<CodecError> for ClientError {
+    fn from(err: CodecError) -> ClientError {
+        match err {
+            CodecError::Io(e) => ClientError::from(e),
+            CodecError::Package(e) => ClientError::Protocol(format!("malformed answer: {}", e)),
+        }
+    }
+}
+
+/// `FancyTalkClient` for use on a tokio runtime
+pub struct AsyncFancyTalkClient {
+    transport: Transport,
+    in_flight: InFlight,
+}
+
+impl AsyncFancyTalkClient {
+    /// Connect to `server`, see `FancyTalkClient::open`
+    pub async fn open(server: &ServerAddress, bind: Option<SocketAddr>, retransmit: Retransmit) -> Result<AsyncFancyTalkClient, ClientError> {
+        let transport = match *server {
+            ServerAddress::Udp(ref address) => {
+                let address = resolve(address).await?;
+                let local = bind.unwrap_or_else(|| match address {
+                    SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+                    SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+                });
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(address).await?;
+                Transport::Udp(socket)
+            },
+            ServerAddress::Tcp(ref address) => {
+                let address = resolve(address).await?;
+                let stream = timeout(retransmit.timeout, TcpStream::connect(address)).await
+                    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connection timed out"))??;
+                Transport::Tcp(Framed::new(stream, PackageCodec::new()))
+            },
+            ServerAddress::Unix(ref path) => Transport::Unix(Framed::new(UnixStream::connect(path).await?, PackageCodec::new())),
+            ServerAddress::UnixDatagram(ref path) => {
+                let bound = BoundDatagram::bind(|path| UnixDatagram::bind(path))?;
+                bound.socket.connect(path)?;
+                Transport::UnixDatagram(bound)
+            },
+        };
+
+        let datagram = match transport {
+            Transport::Udp(_) | Transport::UnixDatagram(_) => true,
+            Transport::Tcp(_) | Transport::Unix(_) => false,
+        };
+        Ok(AsyncFancyTalkClient { transport, in_flight: InFlight::new(retransmit, datagram) })
+    }
+
+    /// Connect to `server` with the default timeout and retries
+    pub async fn connect(server: &ServerAddress) -> Result<AsyncFancyTalkClient, ClientError> {
+        AsyncFancyTalkClient::open(server, None, Retransmit::default()).await
+    }
+
+    /// Change the timeout and retries for queries sent from now on
+    pub fn set_retransmit(&mut self, retransmit: Retransmit) {
+        self.in_flight.set_retransmit(retransmit);
+    }
+
+    /// Number of queries sent but not yet collected with `receive` or `wait_for`
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Send `query` and return the ID it was given
+    pub async fn send(&mut self, query: Package) -> Result<u16, ClientError> {
+        let query = self.in_flight.start(query)?;
+        self.send_package(&query).await?;
+        Ok(query.id)
+    }
+
+    /// Wait for the answer to any query in flight, see `FancyTalkClient::receive`
+    pub async fn receive(&mut self) -> Result<Option<(u16, Package)>, ClientError> {
+        loop {
+            if let Some(answer) = self.in_flight.take_answered(None) {
+                return Ok(Some(answer));
+            }
+            if let Some(id) = self.in_flight.take_timed_out(None) {
+                return Err(ClientError::Timeout { id });
+            }
+            if !self.in_flight.has_pending() {
+                return Ok(None);
+            }
+            if let Some(answer) = self.step().await? {
+                return Ok(Some(answer));
+            }
+        }
+    }
+
+    /// Wait for the answer to the query with ID `id`, see `FancyTalkClient::wait_for`
+    pub async fn wait_for(&mut self, id: u16) -> Result<Package, ClientError> {
+        loop {
+            if let Some((_, answer)) = self.in_flight.take_answered(Some(id)) {
+                return Ok(answer);
+            }
+            if self.in_flight.take_timed_out(Some(id)).is_some() {
+                return Err(ClientError::Timeout { id });
+            }
+            if !self.in_flight.is_pending(id) {
+                return Err(ClientError::Protocol(format!("no query with ID {} is in flight", id)));
+            }
+            if let Some(answer) = self.step().await? {
+                self.in_flight.keep_answered(answer);
+            }
+        }
+    }
+
+    /// Resend what is due, then wait for an answer until the next query is due
+    async fn step(&mut self) -> Result<Option<(u16, Package)>, ClientError> {
+        let now = Instant::now();
+        for query in self.in_flight.expired(now) {
+            self.send_package(&query).await?;
+        }
+        let wait = match self.in_flight.timeout(now) {
+            Some(wait) => wait,
+            None => return Ok(None),
+        };
+        Ok(self.receive_package(wait).await?.and_then(|answer| self.in_flight.matched(answer)))
+    }
+
+    /// Ask the server about `text` and wait for the answer
+    ///
+    /// Error responses are returned as `ClientError::Rejected`.
+    pub async fn query(&mut self, text: &str) -> Result<Package, ClientError> {
+        let id = self.send(new_query(text)).await?;
+        accepted(self.wait_for(id).await?)
+    }
+
+    async fn send_package(&mut self, package: &Package) -> Result<(), ClientError> {
+        match self.transport {
+            Transport::Udp(ref socket) => { socket.send(&encode(package, Framing::Bare)?).await?; },
+            Transport::UnixDatagram(ref bound) => { bound.socket.send(&encode(package, Framing::Bare)?).await?; },
+            Transport::Tcp(ref mut framed) => framed.send(package.clone()).await?,
+            Transport::Unix(ref mut framed) => framed.send(package.clone()).await?,
+        }
+        Ok(())
+    }
+
+    /// Wait up to `wait` for the next package from the server
+    async fn receive_package(&mut self, wait: Duration) -> Result<Option<Package>, ClientError> {
+        let mut in_buf = vec![0u8; MAX_DATAGRAM_LEN];
+        let received = match self.transport {
+            Transport::Udp(ref socket) => timeout(wait, socket.recv(&mut in_buf)).await
+                .map(|amt| decode(&in_buf[..amt?])),
+            Transport::UnixDatagram(ref bound) => timeout(wait, bound.socket.recv(&mut in_buf)).await
+                .map(|amt| decode(&in_buf[..amt?])),
+            Transport::Tcp(ref mut framed) => timeout(wait, framed.next()).await.map(next_frame),
+            Transport::Unix(ref mut framed) => timeout(wait, framed.next()).await.map(next_frame),
+        };
+        match received {
+            Ok(package) => package.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+async fn resolve(address: &str) -> Result<SocketAddr, ClientError> {
+    lookup_host(address).await?.next().ok_or_else(|| {
+        ClientError::Network(io::Error::new(io::ErrorKind::NotFound, format!("{} does not resolve", address)))
+    })
+}
+
+fn next_frame(frame: Option<Result<Package, CodecError>>) -> Result<Package, ClientError> {
+    match frame {
+        Some(package) => Ok(package?),
+        None => {
+            let closed = io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection");
+            Err(ClientError::Network(closed))
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_pipelining() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = ServerAddress::Tcp(listener.local_addr().unwrap().to_string());
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(stream, PackageCodec::new());
+            let first = framed.next().await.unwrap().unwrap();
+            let second = framed.next().await.unwrap().unwrap();
+            // Answer in reverse order, echoing the query
+            for query in [second, first] {
+                let payload = query.query.clone();
+                framed.send(query.set_payload(payload)).await.unwrap();
+            }
+        });
+
+        let mut client = AsyncFancyTalkClient::connect(&address).await.unwrap();
+        let first = client.send(new_query("one")).await.unwrap();
+        let second = client.send(new_query("two")).await.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(client.wait_for(first).await.unwrap().payload.as_deref(), Some("one"));
+        assert_eq!(client.wait_for(second).await.unwrap().payload.as_deref(), Some("two"));
+        assert_eq!(client.in_flight(), 0);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = ServerAddress::Udp(socket.local_addr().unwrap().to_string());
+
+        let retransmit = Retransmit { timeout: Duration::from_millis(700), retries: 2 };
+        let mut client = AsyncFancyTalkClient::open(&address, None, retransmit).await.unwrap();
+        match client.query("greeting").await {
+            Err(ClientError::Timeout { .. }) => (),
+            other => panic!("unexpected {:?}", other.map(|answer| answer.payload)),
+        }
+
+        // All three attempts arrived
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+        for _ in 0..3 {
+            let amt = socket.recv(&mut buf).await.unwrap();
+            assert_eq!(decode(&buf[..amt]).unwrap().query.as_deref(), Some("greeting"));
+        }
+    }
+}
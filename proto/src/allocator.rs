@@ -0,0 +1,63 @@
+use std::alloc::{self, Layout};
+use std::os::raw::c_void;
+use std::ptr;
+use std::sync::{PoisonError, RwLock};
+
+/// Allocation callbacks for the memory the library hands out to C
+///
+/// `alloc` is called with `context` and the number of bytes needed and
+/// returns NULL on failure. Like `malloc()`, it needs to return memory
+/// aligned suitably for any type. `free` is called with `context` and a
+/// pointer returned by `alloc`.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct CAllocator {
+    pub context: *mut c_void,
+    pub alloc: Option<unsafe extern "C" fn(context: *mut c_void, size: usize) -> *mut c_void>,
+    pub free: Option<unsafe extern "C" fn(context: *mut c_void, ptr: *mut c_void)>,
+}
+
+// The context is only ever handed back to the callbacks, whether that is
+// thread safe is up to the C side.
+unsafe impl Send for CAllocator {}
+unsafe impl Sync for CAllocator {}
+
+static ALLOCATOR: RwLock<Option<CAllocator>> = RwLock::new(None);
+
+/// Switch to `allocator`, or back to the Rust allocator for `None`
+pub fn set(allocator: Option<CAllocator>) {
+    *ALLOCATOR.write().unwrap_or_else(PoisonError::into_inner) = allocator;
+}
+
+fn current() -> Option<CAllocator> {
+    *ALLOCATOR.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Allocate `size` bytes aligned to `align`, returns NULL on failure
+pub unsafe fn allocate(size: usize, align: usize) -> *mut u8 {
+    match current() {
+        Some(CAllocator { context, alloc: Some(alloc), free }) => {
+            let ptr = alloc(context, size) as *mut u8;
+            if !ptr.is_null() && !(ptr as usize).is_multiple_of(align) {
+                // Broken callback, don't hand out misaligned structs
+                if let Some(free) = free {
+                    free(context, ptr as *mut c_void);
+                }
+                return ptr::null_mut();
+            }
+            ptr
+        },
+        _ => match Layout::from_size_align(size, align) {
+            Ok(layout) => alloc::alloc(layout),
+            Err(_) => ptr::null_mut(),
+        },
+    }
+}
+
+/// Release memory returned by `allocate()` with the same `size` and `align`
+pub unsafe fn release(ptr: *mut u8, size: usize, align: usize) {
+    match current() {
+        Some(CAllocator { context, free: Some(free), .. }) => free(context, ptr as *mut c_void),
+        _ => alloc::dealloc(ptr, Layout::from_size_align_unchecked(size, align)),
+    }
+}
@@ -0,0 +1,254 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::result;
+
+use serde::Deserialize;
+use toml;
+
+use errors::Error;
+use extension::PROTOCOL_VERSION;
+use style::{ParseStyleError, Style};
+use {MessageType, Package};
+
+/// The catalogue built into the library, see `messages.toml`
+const BUILTIN: &str = include_str!("../messages.toml");
+
+/// Key of the message answered to unknown queries
+const FALLBACK: &str = "fallback";
+
+/// Errors returned when loading a `Catalogue`
+#[derive(Debug)]
+pub enum CatalogueError {
+    /// The catalogue file could not be read
+    Io(io::Error),
+    /// The catalogue is not valid TOML or has unexpected fields
+    Parse(toml::de::Error),
+    /// The style of message `key` could not be parsed
+    Style {
+        key: String,
+        cause: ParseStyleError,
+    },
+    /// Message `key` does not fit into a package
+    Invalid {
+        key: String,
+        cause: Error,
+    },
+    /// The catalogue has no `fallback` message
+    MissingFallback,
+}
+
+impl fmt::Display for CatalogueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CatalogueError::Io(ref e) => write!(f, "reading the catalogue failed: {}", e),
+            CatalogueError::Parse(ref e) => write!(f, "parsing the catalogue failed: {}", e),
+            CatalogueError::Style { ref key, ref cause } => write!(f, "message '{}': {}", key, cause),
+            CatalogueError::Invalid { ref key, ref cause } => write!(f, "message '{}': {}", key, cause),
+            CatalogueError::MissingFallback => write!(f, "catalogue has no '{}' message", FALLBACK),
+        }
+    }
+}
+
+impl error::Error for CatalogueError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            CatalogueError::Io(ref e) => Some(e),
+            CatalogueError::Parse(ref e) => Some(e),
+            CatalogueError::Style { ref cause, .. } => Some(cause),
+            CatalogueError::Invalid { ref cause, .. } => Some(cause),
+            CatalogueError::MissingFallback => None,
+        }
+    }
+}
+
+impl From<io::Error> for CatalogueError {
+    fn from(err: io::Error) -> CatalogueError {
+        CatalogueError::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for CatalogueError {
+    fn from(err: toml::de::Error) -> CatalogueError {
+        CatalogueError::Parse(err)
+    }
+}
+
+/// A message as written in the catalogue file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    payload: Option<String>,
+    #[serde(default)]
+    rgb: [u8; 3],
+    #[serde(default)]
+    style: String,
+}
+
+/// The messages a server answers queries with, keyed by query
+///
+/// Catalogues are read from TOML files with one table per message:
+///
+/// ```toml
+/// [greeting]
+/// payload = "Hello, world!"
+/// rgb = [0xee, 0x66, 0x22]
+/// style = "italic"
+/// ```
+///
+/// A `fallback` message is required, it answers all queries that have no
+/// message of their own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Catalogue {
+    messages: BTreeMap<String, Package>,
+}
+
+impl Catalogue {
+    /// Parse a catalogue from TOML
+    pub fn from_toml(text: &str) -> result::Result<Catalogue, CatalogueError> {
+        let entries: BTreeMap<String, Entry> = toml::from_str(text)?;
+
+        let mut messages = BTreeMap::new();
+        for (key, entry) in entries {
+            let style = match entry.style.parse::<Style>() {
+                Ok(style) => style,
+                Err(cause) => return Err(CatalogueError::Style { key, cause }),
+            };
+            let [red, green, blue] = entry.rgb;
+            let mut message = Package::new().set_message_type(MessageType::Response)
+                                            .set_style(style)
+                                            .set_rgb(red, green, blue)
+                                            .set_payload(entry.payload);
+            message.set_query(Some(key.clone()));
+            if let Err(cause) = message.validate() {
+                return Err(CatalogueError::Invalid { key, cause });
+            }
+            messages.insert(key, message);
+        }
+
+        if !messages.contains_key(FALLBACK) {
+            return Err(CatalogueError::MissingFallback);
+        }
+        Ok(Catalogue { messages })
+    }
+
+    /// Read a catalogue from the TOML file at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> result::Result<Catalogue, CatalogueError> {
+        let text = fs::read_to_string(path)?;
+        Catalogue::from_toml(&text)
+    }
+
+    /// The message stored for `key`
+    pub fn get(&self, key: &str) -> Option<&Package> {
+        self.messages.get(key)
+    }
+
+    /// Keys of all messages, in sorted order
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.messages.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Build the response to query `id` asking for `query`
+    ///
+    /// Unknown or missing queries get the fallback message. The response
+    /// echoes the query and uses the lower of the client's `version` and
+    /// `PROTOCOL_VERSION`. Version 0 clients, which sent no version, get the
+    /// exact same answers as before extensions existed.
+    pub fn answer(&self, id: u16, query: Option<&str>, version: Option<u8>) -> Package {
+        let message = query.and_then(|query| self.get(query))
+                           .unwrap_or_else(|| &self.messages[FALLBACK]);
+
+        let mut response = message.clone().set_id(id);
+        response.set_query(query.map(String::from));
+        if let Some(version) = version {
+            response = response.set_version(cmp::min(version, PROTOCOL_VERSION));
+        }
+        response
+    }
+}
+
+/// The catalogue built into the library
+impl Default for Catalogue {
+    fn default() -> Self {
+        Catalogue::from_toml(BUILTIN).expect("built-in catalogue is valid")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin() {
+        let catalogue = Catalogue::default();
+        let keys: Vec<&str> = catalogue.keys().collect();
+        assert_eq!(keys, ["exit", "fallback", "farewell", "greeting", "hamlet"]);
+
+        let hamlet = catalogue.get("hamlet").unwrap();
+        assert_eq!(hamlet.payload.as_deref(), Some("Alas, poor Yorick!"));
+        assert_eq!(hamlet.style, Style::UNDERLINED);
+        assert_eq!((hamlet.red, hamlet.green, hamlet.blue), (0x00, 0x66, 0x66));
+        assert_eq!(hamlet.message_type, MessageType::Response);
+    }
+
+    #[test]
+    fn test_answer() {
+        let catalogue = Catalogue::default();
+        let response = catalogue.answer(0x2342, Some("greeting"), None);
+        assert_eq!(response.id, 0x2342);
+        assert_eq!(response.query.as_deref(), Some("greeting"));
+        assert_eq!(response.payload.as_deref(), Some("Hello, world!"));
+        assert_eq!(response.version(), None);
+
+        let response = catalogue.answer(1, Some("nonsense"), Some(PROTOCOL_VERSION + 1));
+        assert_eq!(response.query.as_deref(), Some("nonsense"));
+        assert_eq!(response.payload.as_deref(), Some("Not found!"));
+        assert_eq!(response.version(), Some(PROTOCOL_VERSION));
+
+        let response = catalogue.answer(2, None, None);
+        assert_eq!(response.query, None);
+        assert_eq!(response.payload.as_deref(), Some("Not found!"));
+    }
+
+    #[test]
+    fn test_errors() {
+        match Catalogue::from_toml("[greeting]\npayload = \"Hi\"\n") {
+            Err(CatalogueError::MissingFallback) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        match Catalogue::from_toml("[fallback]\nstyle = \"bold|shiny\"\n") {
+            Err(CatalogueError::Style { key, .. }) => assert_eq!(key, "fallback"),
+            other => panic!("unexpected {:?}", other),
+        }
+        match Catalogue::from_toml("[fallback]\ncolour = [1, 2, 3]\n") {
+            Err(CatalogueError::Parse(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        match Catalogue::from_toml("[fallback]\nrgb = [256, 0, 0]\n") {
+            Err(CatalogueError::Parse(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let long = format!("[fallback]\npayload = \"{}\"\n", "x".repeat(u16::MAX as usize + 1));
+        match Catalogue::from_toml(&long) {
+            Err(CatalogueError::Invalid { key, cause }) => {
+                assert_eq!(key, "fallback");
+                assert_eq!(cause.field(), "payload");
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -1,10 +1,25 @@
 use byteorder::{ByteOrder, NetworkEndian};
 
-use errors::{Result};
+use std::str;
+
+use errors::{Error, Result};
+
+/// How to treat the reserved bits of the flags byte when decoding
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DecodeMode {
+    /// Fail if any reserved bit is set
+    Strict,
+    /// Ignore the reserved bits, they are cleared in the decoded package
+    Lenient,
+    /// Keep the reserved bits, so they are written back when encoding
+    #[default]
+    Preserve,
+}
 
 pub struct Decoder<'a> {
     buffer: &'a [u8],
     index: usize,
+    mode: DecodeMode,
 }
 
 impl<'a> Decoder<'a> {
@@ -14,23 +29,73 @@ impl<'a> Decoder<'a> {
     ///
     /// * `buffer` from which all data will be read.
     pub fn new(buffer: &'a [u8]) -> Self {
+        Decoder::with_mode(buffer, DecodeMode::default())
+    }
+
+    /// Create a new `Decoder` using the given `DecodeMode`
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` from which all data will be read.
+    /// * `mode` deciding what happens to reserved bits.
+    pub fn with_mode(buffer: &'a [u8], mode: DecodeMode) -> Self {
         Decoder {
-            buffer: buffer,
+            buffer,
             index: 0,
+            mode,
+        }
+    }
+
+    /// The `DecodeMode` used by this decoder
+    pub fn mode(&self) -> DecodeMode {
+        self.mode
+    }
+
+    /// Number of bytes left to read
+    pub fn remaining(&self) -> usize {
+        self.buffer.len() - self.index
+    }
+
+    /// Offset of the next byte to read
+    pub fn position(&self) -> usize {
+        self.index
+    }
+
+    fn truncated(&self, needed: usize) -> Error {
+        Error::Truncated {
+            field: "data",
+            offset: self.index,
+            needed,
+            available: self.remaining(),
         }
     }
 
     /// Read a larger slice from the buffer
     pub fn read_slice(&mut self, length: usize) -> Result<&'a [u8]> {
-        let end = self.index + length;
-        if end > self.buffer.len() {
-            bail!("Buffer drained")
+        if length > self.remaining() {
+            return Err(self.truncated(length));
         }
+        let end = self.index + length;
         let slice: &'a [u8] = &self.buffer[self.index..end];
         self.index += length;
         Ok(slice)
     }
 
+    /// Read a UTF-8 string of `length` bytes from the buffer
+    pub fn read_str(&mut self, length: usize) -> Result<&'a str> {
+        let start = self.index;
+        let raw = self.read_slice(length)?;
+        str::from_utf8(raw).map_err(|cause| {
+            // Don't skip the broken string
+            self.index = start;
+            Error::InvalidUtf8 {
+                field: "data",
+                offset: start + cause.valid_up_to(),
+                cause,
+            }
+        })
+    }
+
     /// Read a byte from the buffer
     pub fn read_u8(&mut self) -> Result<u8> {
         // No need for using a slice
@@ -39,7 +104,7 @@ impl<'a> Decoder<'a> {
             self.index += 1;
             Ok(byte)
         } else {
-            bail!("Index error")
+            Err(self.truncated(1))
         }
     }
 
@@ -101,10 +166,19 @@ impl<'a> Encoder<'a> {
         self.write_slice(&encoded)
     }
 
+    /// Reserve space for at least `additional` more bytes
+    pub fn reserve(&mut self, additional: usize) {
+        self.buffer.reserve(additional);
+    }
+
     pub fn len(&self) -> usize {
         self.buffer.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
     pub fn into_bytes(self) -> &'a Vec<u8> {
         self.buffer
     }
@@ -114,6 +188,8 @@ impl<'a> Encoder<'a> {
 pub trait Serialisable<T: Sized> {
     fn read(decoder: &mut Decoder) -> Result<T>;
     fn write(&self, encoder: &mut Encoder) -> Result<usize>;
+    /// Number of bytes `write()` will produce
+    fn size_hint(&self) -> usize;
 }
 
 impl Serialisable<u16> for u16 {
@@ -124,6 +200,10 @@ impl Serialisable<u16> for u16 {
     fn write(&self, encoder: &mut Encoder) -> Result<usize> {
         encoder.write_u16(*self)
     }
+
+    fn size_hint(&self) -> usize {
+        2
+    }
 }
 
 impl Serialisable<u32> for u32 {
@@ -134,6 +214,10 @@ impl Serialisable<u32> for u32 {
     fn write(&self, encoder: &mut Encoder) -> Result<usize> {
         encoder.write_u32(*self)
     }
+
+    fn size_hint(&self) -> usize {
+        4
+    }
 }
 
 
@@ -157,7 +241,22 @@ mod tests {
         let read = decoder.read_u8().expect("Failed to read '!'");
         assert_eq!(read, 0x21);
 
-        assert!(decoder.read_u8().is_err());
+        assert_eq!(decoder.read_u8().unwrap_err(),
+                   Error::Truncated { field: "data", offset: 9, needed: 1, available: 0 });
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let mut decoder = Decoder::new(b"\x00\x05ab\xc3\x28");
+        assert_eq!(decoder.read_u16().unwrap(), 5);
+        assert_eq!(decoder.read_slice(5).unwrap_err(),
+                   Error::Truncated { field: "data", offset: 2, needed: 5, available: 4 });
+        assert_eq!(decoder.position(), 2);
+
+        let err = decoder.read_str(4).unwrap_err();
+        assert_eq!(err.offset(), Some(4));
+        assert_eq!(decoder.position(), 2);
+        assert_eq!(decoder.read_str(2).unwrap(), "ab");
     }
 
     #[test]
@@ -173,4 +272,13 @@ mod tests {
 
         assert_eq!(encoder.buffer, expected);
     }
+
+    #[test]
+    fn test_size_hint() {
+        let mut result: Vec<u8> = Vec::new();
+        let mut encoder = Encoder::new(&mut result);
+
+        assert_eq!(0x6164_u16.write(&mut encoder).unwrap(), 0x6164_u16.size_hint());
+        assert_eq!(0x62656566_u32.write(&mut encoder).unwrap(), 0x62656566_u32.size_hint());
+    }
 }
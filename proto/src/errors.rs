@@ -0,0 +1,184 @@
+use std::error;
+use std::fmt;
+use std::result;
+use std::str::Utf8Error;
+
+/// Errors returned when decoding or encoding a package
+///
+/// `field` names the part of the package that failed, e.g. `"ID"` or
+/// `"query"`. Offsets are byte offsets into the buffer the `Decoder` reads
+/// from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The buffer ended before `field` was complete
+    Truncated {
+        field: &'static str,
+        offset: usize,
+        needed: usize,
+        available: usize,
+    },
+    /// `field` is not valid UTF-8
+    ///
+    /// `offset` points at the first byte that is not part of a valid
+    /// UTF-8 sequence.
+    InvalidUtf8 {
+        field: &'static str,
+        offset: usize,
+        cause: Utf8Error,
+    },
+    /// Reserved bits were set while decoding in `DecodeMode::Strict`
+    ReservedBits {
+        offset: usize,
+        bits: u8,
+    },
+    /// `field` is too long for its u16 length prefix
+    LengthOverflow {
+        field: &'static str,
+        len: usize,
+    },
+    /// A length prefix announced a frame larger than any valid package
+    FrameTooLong {
+        len: usize,
+    },
+    /// A required pointer was NULL, only returned by the C API
+    NullPointer {
+        field: &'static str,
+    },
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    /// The part of the package this error is about
+    pub fn field(&self) -> &'static str {
+        match *self {
+            Error::Truncated { field, .. } |
+            Error::InvalidUtf8 { field, .. } |
+            Error::LengthOverflow { field, .. } |
+            Error::NullPointer { field } => field,
+            Error::ReservedBits { .. } => "flags",
+            Error::FrameTooLong { .. } => "frame",
+        }
+    }
+
+    /// Stable numeric code for the kind of error, as sent in error responses
+    pub fn code(&self) -> u8 {
+        match *self {
+            Error::Truncated { .. } => 1,
+            Error::InvalidUtf8 { .. } => 2,
+            Error::ReservedBits { .. } => 3,
+            Error::LengthOverflow { .. } => 4,
+            Error::FrameTooLong { .. } => 5,
+            Error::NullPointer { .. } => 6,
+        }
+    }
+
+    /// Offset into the decoded buffer, for errors that happen while decoding
+    pub fn offset(&self) -> Option<usize> {
+        match *self {
+            Error::Truncated { offset, .. } |
+            Error::InvalidUtf8 { offset, .. } |
+            Error::ReservedBits { offset, .. } => Some(offset),
+            Error::LengthOverflow { .. } |
+            Error::FrameTooLong { .. } |
+            Error::NullPointer { .. } => None,
+        }
+    }
+
+    /// Attribute the error to `name` instead of the field it was raised for
+    pub(crate) fn in_field(mut self, name: &'static str) -> Self {
+        match self {
+            Error::Truncated { ref mut field, .. } |
+            Error::InvalidUtf8 { ref mut field, .. } |
+            Error::LengthOverflow { ref mut field, .. } |
+            Error::NullPointer { ref mut field } => *field = name,
+            Error::ReservedBits { .. } |
+            Error::FrameTooLong { .. } => {},
+        }
+        self
+    }
+
+    /// Move the offset by `start`, for errors from a decoder over a sub-slice
+    pub(crate) fn shift(mut self, start: usize) -> Self {
+        match self {
+            Error::Truncated { ref mut offset, .. } |
+            Error::InvalidUtf8 { ref mut offset, .. } |
+            Error::ReservedBits { ref mut offset, .. } => *offset += start,
+            Error::LengthOverflow { .. } |
+            Error::FrameTooLong { .. } |
+            Error::NullPointer { .. } => {},
+        }
+        self
+    }
+}
+
+/// Shorthand for `map_err(|e| e.in_field(name))`
+pub(crate) trait ResultExt {
+    fn field(self, name: &'static str) -> Self;
+}
+
+impl<T> ResultExt for Result<T> {
+    fn field(self, name: &'static str) -> Self {
+        self.map_err(|e| e.in_field(name))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Truncated { field, offset, needed, available } =>
+                write!(f, "{} truncated at offset {}: needs {} bytes, {} available",
+                       field, offset, needed, available),
+            Error::InvalidUtf8 { field, offset, .. } =>
+                write!(f, "{} is not valid utf-8 at offset {}", field, offset),
+            Error::ReservedBits { offset, bits } =>
+                write!(f, "reserved bits set at offset {}: {:#04x}", offset, bits),
+            Error::LengthOverflow { field, len } =>
+                write!(f, "{} is {} bytes long, at most {} fit the wire format", field, len, u16::MAX),
+            Error::FrameTooLong { len } =>
+                write!(f, "frame of {} bytes is longer than any valid package", len),
+            Error::NullPointer { field } =>
+                write!(f, "{} is a null pointer", field),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::InvalidUtf8 { ref cause, .. } => Some(cause),
+            _ => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as StdError;
+    use std::str;
+
+    #[test]
+    fn test_field_and_offset() {
+        let err = Error::Truncated { field: "data", offset: 3, needed: 4, available: 1 };
+        let err = err.in_field("query").shift(6);
+        assert_eq!(err.field(), "query");
+        assert_eq!(err.offset(), Some(9));
+        assert_eq!(err.to_string(), "query truncated at offset 9: needs 4 bytes, 1 available");
+
+        let err = Error::LengthOverflow { field: "payload", len: 70000 };
+        assert_eq!(err.clone().shift(6), err);
+        assert_eq!(err.offset(), None);
+    }
+
+    #[test]
+    fn test_source() {
+        let broken = vec![0xc3, 0x28];
+        let cause = str::from_utf8(&broken).unwrap_err();
+        let err = Error::InvalidUtf8 { field: "query", offset: 8, cause };
+        assert_eq!(err.to_string(), "query is not valid utf-8 at offset 8");
+        assert!(err.source().is_some());
+        assert!(Error::NullPointer { field: "buffer" }.source().is_none());
+    }
+}
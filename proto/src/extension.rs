@@ -0,0 +1,206 @@
+use codec::{Decoder, Encoder};
+use errors::{Result, ResultExt};
+
+/// The protocol version implemented by this library
+///
+/// Packages without a version extension are version 0, the original
+/// SambaXP 2018 protocol.
+pub const PROTOCOL_VERSION : u8 = 1;
+
+/// An extension record from the extension block following the payload
+///
+/// Records of unknown kinds are kept as they are, so they can be forwarded
+/// untouched.
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct Extension {
+    pub kind: u16,
+    pub data: Vec<u8>,
+}
+
+impl Extension {
+    /// Highest protocol version spoken by the sender, a single byte
+    pub const VERSION: u16 = 0x0001;
+    /// Marks a response to a malformed query, a single byte holding the
+    /// `Error::code()` of the failure
+    pub const ERROR: u16 = 0x0002;
+
+    pub fn new(kind: u16, data: Vec<u8>) -> Self {
+        Extension { kind, data }
+    }
+
+    /// Create a `VERSION` extension
+    pub fn version(version: u8) -> Self {
+        Extension::new(Extension::VERSION, vec![version])
+    }
+}
+
+impl<'a> From<ExtensionRef<'a>> for Extension {
+    fn from(ext: ExtensionRef<'a>) -> Extension {
+        Extension::new(ext.kind, ext.data.to_vec())
+    }
+}
+
+/// Borrowed version of an `Extension`
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub struct ExtensionRef<'a> {
+    pub kind: u16,
+    pub data: &'a [u8],
+}
+
+/// Borrowed, already validated extension records
+#[derive(Copy, Clone, Debug, Default, PartialEq, PartialOrd)]
+pub struct Extensions<'a> {
+    records: &'a [u8],
+}
+
+impl<'a> Extensions<'a> {
+    /// Validate the encoded extension records in `records`
+    pub fn parse(records: &'a [u8]) -> Result<Self> {
+        let mut decoder = Decoder::new(records);
+        while decoder.remaining() > 0 {
+            read_record(&mut decoder)?;
+        }
+        Ok(Extensions { records })
+    }
+
+    /// The encoded records, without the block length
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.records
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn iter(&self) -> ExtensionIter<'a> {
+        ExtensionIter { decoder: Decoder::new(self.records) }
+    }
+
+    /// Data of the first record of the given kind
+    pub fn get(&self, kind: u16) -> Option<&'a [u8]> {
+        self.iter().find(|ext| ext.kind == kind).map(|ext| ext.data)
+    }
+
+    /// The protocol version announced by the sender, if any
+    pub fn version(&self) -> Option<u8> {
+        self.get(Extension::VERSION).and_then(|data| data.first().cloned())
+    }
+
+    /// The error code of an error response, if this is one
+    pub fn error_code(&self) -> Option<u8> {
+        self.get(Extension::ERROR).and_then(|data| data.first().cloned())
+    }
+}
+
+/// Iterator over the records of `Extensions`
+pub struct ExtensionIter<'a> {
+    decoder: Decoder<'a>,
+}
+
+impl<'a> Iterator for ExtensionIter<'a> {
+    type Item = ExtensionRef<'a>;
+
+    fn next(&mut self) -> Option<ExtensionRef<'a>> {
+        if self.decoder.remaining() == 0 {
+            return None;
+        }
+        // Records were validated in Extensions::parse()
+        read_record(&mut self.decoder).ok()
+    }
+}
+
+fn read_record<'a>(decoder: &mut Decoder<'a>) -> Result<ExtensionRef<'a>> {
+    let kind = decoder.read_u16().field("extension kind")?;
+    let len = decoder.read_u16().field("extension length")?;
+    let data = decoder.read_slice(len as usize).field("extension data")?;
+    Ok(ExtensionRef { kind, data })
+}
+
+/// Read the extension block, a u16 length followed by the records
+pub fn read_block<'a>(decoder: &mut Decoder<'a>) -> Result<Extensions<'a>> {
+    let len = decoder.read_u16().field("extension block length")?;
+    let start = decoder.position();
+    let records = decoder.read_slice(len as usize).field("extension block")?;
+    // Report offsets relative to the whole package, not the block
+    Extensions::parse(records).map_err(|e| e.shift(start))
+}
+
+/// Write `extensions` as records, without the block length
+///
+/// Lengths are not checked, use `Package::validate()` first.
+pub fn write_records(extensions: &[Extension], encoder: &mut Encoder) -> Result<usize> {
+    let mut written = 0;
+    for ext in extensions {
+        written += encoder.write_u16(ext.kind)?;
+        written += encoder.write_u16(ext.data.len() as u16)?;
+        written += encoder.write_slice(&ext.data)?;
+    }
+    Ok(written)
+}
+
+/// Length of the extension block for `extensions`, without its length prefix
+pub fn block_len(extensions: &[Extension]) -> usize {
+    extensions.iter().map(|ext| 4 + ext.data.len()).sum()
+}
+
+/// Write the extension block for `extensions`
+///
+/// Lengths are not checked, use `Package::validate()` first.
+pub fn write_block(extensions: &[Extension], encoder: &mut Encoder) -> Result<usize> {
+    let mut written = encoder.write_u16(block_len(extensions) as u16)?;
+    written += write_records(extensions, encoder)?;
+    Ok(written)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Error;
+
+    #[test]
+    fn test_parse() {
+        let records = vec![
+            0x00, 0x01,  // kind: version
+            0x00, 0x01,  // len
+            0x01,
+            0x12, 0x34,  // kind: unknown
+            0x00, 0x02,  // len
+            0xab, 0xcd,
+        ];
+
+        let extensions = Extensions::parse(&records).unwrap();
+        let kinds : Vec<u16> = extensions.iter().map(|ext| ext.kind).collect();
+        assert_eq!(kinds, vec![Extension::VERSION, 0x1234]);
+        assert_eq!(extensions.get(0x1234), Some(&records[9..]));
+        assert_eq!(extensions.version(), Some(1));
+
+        let err = Extensions::parse(&records[..10]).unwrap_err();
+        assert_eq!(err, Error::Truncated { field: "extension data", offset: 9, needed: 2, available: 1 });
+        assert_eq!(Extensions::parse(&records[..3]).unwrap_err().field(), "extension length");
+        assert_eq!(Extensions::parse(&[]).unwrap().version(), None);
+    }
+
+    #[test]
+    fn test_write() {
+        let extensions = vec![Extension::version(1), Extension::new(0x1234, vec![0xab, 0xcd])];
+        let expected = vec![
+            0x00, 0x0b,  // block len
+            0x00, 0x01,  // kind: version
+            0x00, 0x01,  // len
+            0x01,
+            0x12, 0x34,  // kind: unknown
+            0x00, 0x02,  // len
+            0xab, 0xcd,
+        ];
+
+        let mut buffer: Vec<u8> = Vec::new();
+        let written = write_block(&extensions, &mut Encoder::new(&mut buffer)).unwrap();
+        assert_eq!(written, expected.len());
+        assert_eq!(buffer, expected);
+
+        let mut decoder = Decoder::new(&buffer);
+        let read : Vec<Extension> = read_block(&mut decoder).unwrap().iter().map(Extension::from).collect();
+        assert_eq!(read, extensions);
+    }
+}
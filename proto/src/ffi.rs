@@ -0,0 +1,907 @@
+use std::cell::RefCell;
+use std::mem;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::str;
+
+use allocator::{self, CAllocator};
+use catalogue::Catalogue;
+use codec::{Decoder, Encoder, Serialisable};
+use errors::{Error, Result, ResultExt};
+use extension::{self, Extension, Extensions};
+use {MessageType, Package, Style};
+
+/// Status codes returned by the C API
+///
+/// Whenever a call returns something other than `Ok`, a description of the
+/// failure can be fetched with `last_error_message()`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CStatus {
+    Ok = 0,
+    /// A required pointer argument was NULL
+    NullPointer = 1,
+    /// The buffer ended before the package was complete
+    Truncated = 2,
+    /// Query or payload are not valid UTF-8
+    BadUtf8 = 3,
+    /// Query or payload are too long for their u16 length prefix
+    LengthOverflow = 4,
+    /// The library panicked, which is a bug in the library
+    Panic = 5,
+    /// Any other failure
+    Other = 6,
+    /// A style string could not be parsed
+    InvalidStyle = 7,
+    /// The caller-provided buffer is too small
+    BufferTooSmall = 8,
+    /// Allocating memory for the result failed
+    OutOfMemory = 9,
+    /// A message catalogue could not be read or is invalid
+    InvalidCatalogue = 10,
+}
+
+impl From<&Error> for CStatus {
+    fn from(err: &Error) -> CStatus {
+        match *err {
+            Error::Truncated { .. } => CStatus::Truncated,
+            Error::InvalidUtf8 { .. } => CStatus::BadUtf8,
+            Error::LengthOverflow { .. } => CStatus::LengthOverflow,
+            Error::NullPointer { .. } => CStatus::NullPointer,
+            Error::ReservedBits { .. } |
+            Error::FrameTooLong { .. } => CStatus::Other,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: &str) {
+    // CString can't hold interior NUL bytes, and C would stop reading there anyway
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn fail_with(status: CStatus, message: &str) -> CStatus {
+    set_last_error(message);
+    status
+}
+
+fn fail(err: &Error) -> CStatus {
+    fail_with(CStatus::from(err), &err.to_string())
+}
+
+/// Run `f`, turning any panic into `CStatus::Panic` instead of unwinding into C
+fn guard<F: FnOnce() -> CStatus>(f: F) -> CStatus {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(status) => status,
+        Err(cause) => {
+            let reason = if let Some(msg) = cause.downcast_ref::<&str>() {
+                msg.to_string()
+            } else if let Some(msg) = cause.downcast_ref::<String>() {
+                msg.clone()
+            } else {
+                String::from("unknown cause")
+            };
+            fail_with(CStatus::Panic, &format!("panic in fancy_talk: {}", reason))
+        },
+    }
+}
+
+/// Get a description of the last error that occurred on this thread
+///
+/// Returns NULL if no call has failed yet. The string is owned by the library
+/// and stays valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| match *last.borrow() {
+        Some(ref message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Use `allocator` for all memory handed out by the library
+///
+/// Packages returned by `decode_package()` and buffers returned by
+/// `encode_package()` are then allocated with the `alloc` hook, a single
+/// allocation each, and can be released with the `free` hook directly
+/// instead of `free_package()` and `free_buffer()`. Passing NULL switches
+/// back to the built-in allocator.
+///
+/// The allocator is global. Set it before any other call and don't change
+/// it while the library still owns memory from the previous one.
+///
+/// # Safety
+///
+/// `allocator` must be NULL or point to a valid `CAllocator`, whose hooks
+/// must be safe to call from any thread the library is used on.
+#[no_mangle]
+pub unsafe extern "C" fn set_allocator(allocator: *const CAllocator) -> CStatus {
+    guard(|| {
+        if allocator.is_null() {
+            allocator::set(None);
+            return CStatus::Ok;
+        }
+        let hooks = *allocator;
+        if hooks.alloc.is_none() || hooks.free.is_none() {
+            return fail_with(CStatus::NullPointer, "allocator needs both alloc and free hooks");
+        }
+        allocator::set(Some(hooks));
+        CStatus::Ok
+    })
+}
+
+
+/// Style bits for `CPackage.style`, can be combined with `|`
+pub const STYLE_BOLD: u8 = 0b0100_0000;
+pub const STYLE_ITALIC: u8 = 0b0010_0000;
+pub const STYLE_UNDERLINED: u8 = 0b0001_0000;
+pub const STYLE_BLINK: u8 = 0b0000_1000;
+pub const STYLE_RESERVED: u8 = 0b0000_0011;
+
+/// C representation of a `Package`
+///
+/// Ownership depends on where a package comes from. Packages returned by
+/// `decode_package()` belong to the library: query, payload and extensions
+/// are allocated in a single block together with the package, are
+/// NUL-terminated (the NUL is not counted in the lengths) and are all
+/// released by `free_package()`, or by the `free` hook of the allocator set
+/// with `set_allocator()`. Their fields must not be changed before that.
+/// Packages built by C, e.g. to pass to `encode_package()`, stay owned by C
+/// and must never be passed to `free_package()`.
+#[repr(C)]
+pub struct CPackage {
+    pub id: u16,
+    pub message_type: u8,
+    pub style: u8,
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub query_len: usize,
+    pub query: *mut u8,
+    pub payload_len: usize,
+    pub payload: *mut u8,
+    /// Encoded extension records, passed through untouched
+    ///
+    /// A non-NULL `extensions` with `extensions_len` 0 stands for an empty
+    /// extension block, which is written back as such. NULL means there is
+    /// no extension block unless `extensions_len` says otherwise.
+    pub extensions_len: usize,
+    pub extensions: *mut u8,
+}
+
+unsafe fn string_from_c(data: *const u8, len: usize) -> Result<Option<String>> {
+    if len == 0 {
+        return Ok(None);
+    }
+    if data.is_null() {
+        return Err(Error::NullPointer { field: "data" });
+    }
+    match str::from_utf8(slice::from_raw_parts(data, len)) {
+        Ok(text) => Ok(Some(String::from(text))),
+        Err(cause) => Err(Error::InvalidUtf8 { field: "data", offset: cause.valid_up_to(), cause }),
+    }
+}
+
+impl Package {
+    /// Create a `Package` from a `CPackage` handed in from C
+    ///
+    /// # Safety
+    ///
+    /// `query` and `payload` need to point to at least `query_len` and
+    /// `payload_len` readable bytes, respectively.
+    pub unsafe fn from_c(c_pkg: &CPackage) -> Result<Self> {
+        let message_type = if c_pkg.message_type == 0 {
+            MessageType::Query
+        } else {
+            MessageType::Response
+        };
+        let query = string_from_c(c_pkg.query, c_pkg.query_len).field("query")?;
+        let payload = string_from_c(c_pkg.payload, c_pkg.payload_len).field("payload")?;
+        let extensions = if c_pkg.extensions_len > 0 {
+            if c_pkg.extensions.is_null() {
+                return Err(Error::NullPointer { field: "extensions" });
+            }
+            let records = slice::from_raw_parts(c_pkg.extensions, c_pkg.extensions_len);
+            Extensions::parse(records)?.iter().map(Extension::from).collect()
+        } else {
+            Vec::new()
+        };
+        Ok(Package {
+            id: c_pkg.id,
+            message_type,
+            style: Style::from_bits(c_pkg.style),
+            red: c_pkg.red,
+            green: c_pkg.green,
+            blue: c_pkg.blue,
+            query,
+            payload,
+            extensions,
+            empty_extension_block: c_pkg.extensions_len == 0 && !c_pkg.extensions.is_null(),
+        })
+    }
+}
+
+/// Size of the block holding a `CPackage` and the data it points to
+fn c_package_size(query_len: Option<usize>, payload_len: Option<usize>, extensions_len: Option<usize>) -> usize {
+    // Each string is followed by a NUL byte
+    let data : usize = [query_len, payload_len, extensions_len].iter().flatten().map(|len| len + 1).sum();
+    mem::size_of::<CPackage>() + data
+}
+
+/// Copy `data` to `*cursor` followed by a NUL byte, advancing `*cursor`
+unsafe fn copy_into_c(data: &[u8], cursor: &mut *mut u8) -> (usize, *mut u8) {
+    let start = *cursor;
+    ptr::copy_nonoverlapping(data.as_ptr(), start, data.len());
+    *start.add(data.len()) = 0;
+    *cursor = start.add(data.len() + 1);
+    (data.len(), start)
+}
+
+/// Hand `pkg` over to C
+///
+/// The `CPackage` and its query, payload and extensions are allocated as a
+/// single block through the configured allocator, so C can release all of
+/// it with one call to its own free function. Returns NULL if the
+/// allocation failed.
+unsafe fn package_into_c(pkg: Package) -> *mut CPackage {
+    let mut records : Vec<u8> = Vec::new();
+    extension::write_records(&pkg.extensions, &mut Encoder::new(&mut records))
+        .expect("encoding into a Vec never fails");
+    let query = pkg.query.as_ref().map(|q| q.as_bytes());
+    let payload = pkg.payload.as_ref().map(|p| p.as_bytes());
+    let extensions = if pkg.has_extension_block() { Some(&records[..]) } else { None };
+
+    let size = c_package_size(query.map(<[u8]>::len), payload.map(<[u8]>::len), extensions.map(<[u8]>::len));
+    let block = allocator::allocate(size, mem::align_of::<CPackage>());
+    if block.is_null() {
+        return ptr::null_mut();
+    }
+
+    let mut cursor = block.add(mem::size_of::<CPackage>());
+    let (q_len, q_ptr) = query.map_or((0, ptr::null_mut()), |q| copy_into_c(q, &mut cursor));
+    let (p_len, p_ptr) = payload.map_or((0, ptr::null_mut()), |p| copy_into_c(p, &mut cursor));
+    let (e_len, e_ptr) = extensions.map_or((0, ptr::null_mut()), |e| copy_into_c(e, &mut cursor));
+
+    let msg_type : u8 = match pkg.message_type {
+        MessageType::Query => 0,
+        MessageType::Response => 1
+    };
+    let c_pkg = block as *mut CPackage;
+    ptr::write(c_pkg, CPackage {
+        id: pkg.id,
+        message_type: msg_type,
+        style: pkg.style.bits(),
+        red: pkg.red,
+        green: pkg.green,
+        blue: pkg.blue,
+        query_len: q_len,
+        query: q_ptr,
+        payload_len: p_len,
+        payload: p_ptr,
+        extensions_len: e_len,
+        extensions: e_ptr,
+    });
+    c_pkg
+}
+
+/// Decode the package in `buffer` into a newly allocated `CPackage`
+///
+/// On success, `*package` points to the decoded package, which needs to be
+/// released with `free_package()`. On failure, `*package` is set to NULL.
+///
+/// # Safety
+///
+/// `buffer` needs to point to at least `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn decode_package(buffer: *const u8, len: usize, package: *mut *mut CPackage) -> CStatus {
+    guard(|| {
+        if buffer.is_null() || package.is_null() {
+            return fail_with(CStatus::NullPointer, "null pointer argument");
+        }
+        *package = ptr::null_mut();
+
+        let buf : &[u8] = slice::from_raw_parts(buffer, len);
+        let mut decoder = Decoder::new(buf);
+        match Package::read(&mut decoder) {
+            Ok(pkg) => {
+                *package = package_into_c(pkg);
+                if (*package).is_null() {
+                    return fail_with(CStatus::OutOfMemory, "allocating the package failed");
+                }
+                CStatus::Ok
+            },
+            Err(e) => fail(&e),
+        }
+    })
+}
+
+/// Encode `package` into a newly allocated buffer
+///
+/// On success, `*buffer` points to the encoded package, which needs to be
+/// released with `free_buffer()` (or the `free` hook of the allocator), and `*len` holds its length. Packages with
+/// a query, payload or extension too long for the wire format are rejected
+/// with `LengthOverflow`.
+///
+/// # Safety
+///
+/// `package` needs to point to a valid `CPackage`.
+#[no_mangle]
+pub unsafe extern "C" fn encode_package(package: *const CPackage, buffer: *mut *mut u8, len: *mut usize) -> CStatus {
+    guard(|| {
+        if package.is_null() || buffer.is_null() || len.is_null() {
+            return fail_with(CStatus::NullPointer, "null pointer argument");
+        }
+
+        let c_pkg : &CPackage = &*package;
+        let pkg = match Package::from_c(c_pkg) {
+            Ok(pkg) => pkg,
+            Err(e) => return fail(&e),
+        };
+
+        let mut buf : Vec<u8> = Vec::with_capacity(pkg.encoded_len());
+        let written = {
+            let mut encoder = Encoder::new(&mut buf);
+            match pkg.write(&mut encoder) {
+                Ok(written) => written,
+                Err(e) => return fail(&e),
+            }
+        };
+
+        let data = allocator::allocate(written, 1);
+        if data.is_null() {
+            return fail_with(CStatus::OutOfMemory, "allocating the buffer failed");
+        }
+        ptr::copy_nonoverlapping(buf.as_ptr(), data, written);
+        *len = written;
+        *buffer = data;
+        CStatus::Ok
+    })
+}
+
+/// Encode `package` into the caller-provided `buffer` of `capacity` bytes
+///
+/// On success, `*written` holds the number of bytes written. If the buffer is
+/// too small, `BufferTooSmall` is returned and `*written` holds the number of
+/// bytes needed, so `buffer` may be NULL with a `capacity` of 0 to query the
+/// size up front.
+///
+/// # Safety
+///
+/// `package` needs to point to a valid `CPackage`, `buffer` needs to point to
+/// at least `capacity` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn encode_package_into(package: *const CPackage, buffer: *mut u8, capacity: usize, written: *mut usize) -> CStatus {
+    guard(|| {
+        if package.is_null() || written.is_null() {
+            return fail_with(CStatus::NullPointer, "null pointer argument");
+        }
+        *written = 0;
+
+        let pkg = match Package::from_c(&*package) {
+            Ok(pkg) => pkg,
+            Err(e) => return fail(&e),
+        };
+        if let Err(e) = pkg.validate() {
+            return fail(&e);
+        }
+
+        let needed = pkg.encoded_len();
+        if needed > capacity {
+            *written = needed;
+            return fail_with(CStatus::BufferTooSmall,
+                             &format!("encoding needs {} bytes, buffer only holds {}", needed, capacity));
+        }
+        if buffer.is_null() {
+            return fail_with(CStatus::NullPointer, "null pointer argument");
+        }
+
+        let mut buf : Vec<u8> = Vec::with_capacity(needed);
+        let len = {
+            let mut encoder = Encoder::new(&mut buf);
+            match pkg.write(&mut encoder) {
+                Ok(len) => len,
+                Err(e) => return fail(&e),
+            }
+        };
+        ptr::copy_nonoverlapping(buf.as_ptr(), buffer, len);
+        *written = len;
+        CStatus::Ok
+    })
+}
+
+/// Parse a style string like "bold|blink" into style bits
+///
+/// # Safety
+///
+/// `text` needs to be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn parse_style(text: *const c_char, style: *mut u8) -> CStatus {
+    guard(|| {
+        if text.is_null() || style.is_null() {
+            return fail_with(CStatus::NullPointer, "null pointer argument");
+        }
+
+        let text = match CStr::from_ptr(text).to_str() {
+            Ok(text) => text,
+            Err(e) => return fail_with(CStatus::BadUtf8, &format!("converting the style failed: {}", e)),
+        };
+        match text.parse::<Style>() {
+            Ok(parsed) => {
+                *style = parsed.bits();
+                CStatus::Ok
+            },
+            Err(e) => fail_with(CStatus::InvalidStyle, &e.to_string()),
+        }
+    })
+}
+
+/// Borrow the query of `package`
+///
+/// Returns NULL if the package has no query. For packages returned by
+/// `decode_package()` the query is NUL-terminated. If `len` is not NULL,
+/// `*len` is set to the query length, not counting the NUL byte.
+///
+/// # Safety
+///
+/// `package` needs to point to a valid `CPackage`. The returned pointer is
+/// only valid as long as the package is.
+#[no_mangle]
+pub unsafe extern "C" fn package_query(package: *const CPackage, len: *mut usize) -> *const c_char {
+    if package.is_null() {
+        return borrow_c_bytes(ptr::null(), 0, len);
+    }
+    borrow_c_bytes((*package).query, (*package).query_len, len)
+}
+
+/// Borrow the payload of `package`
+///
+/// Works like `package_query()`.
+///
+/// # Safety
+///
+/// `package` needs to point to a valid `CPackage`. The returned pointer is
+/// only valid as long as the package is.
+#[no_mangle]
+pub unsafe extern "C" fn package_payload(package: *const CPackage, len: *mut usize) -> *const c_char {
+    if package.is_null() {
+        return borrow_c_bytes(ptr::null(), 0, len);
+    }
+    borrow_c_bytes((*package).payload, (*package).payload_len, len)
+}
+
+unsafe fn borrow_c_bytes(data: *const u8, data_len: usize, len: *mut usize) -> *const c_char {
+    let data = if data_len == 0 { ptr::null() } else { data };
+    if !len.is_null() {
+        *len = if data.is_null() { 0 } else { data_len };
+    }
+    data as *const c_char
+}
+
+/// Release a `CPackage` returned by `decode_package()`
+///
+/// This releases query, payload and extensions as well, so pointers
+/// borrowed from the package are invalid afterwards.
+///
+/// # Safety
+///
+/// `package` must be NULL or a pointer returned by `decode_package()` that
+/// was not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn free_package(package: *mut CPackage) {
+    guard(|| {
+        if !package.is_null() {
+            let pkg = &*package;
+            let len_of = |data: *mut u8, len: usize| if data.is_null() { None } else { Some(len) };
+            let size = c_package_size(len_of(pkg.query, pkg.query_len),
+                                      len_of(pkg.payload, pkg.payload_len),
+                                      len_of(pkg.extensions, pkg.extensions_len));
+            allocator::release(package as *mut u8, size, mem::align_of::<CPackage>());
+        }
+        CStatus::Ok
+    });
+}
+
+/// Release a buffer returned by `encode_package()`
+///
+/// # Safety
+///
+/// `buffer` must be NULL or a pointer returned by `encode_package()` that
+/// was not freed before, and `len` the length returned along with it.
+#[no_mangle]
+pub unsafe extern "C" fn free_buffer(buffer: *mut u8, len: usize) {
+    guard(|| {
+        if !buffer.is_null() {
+            allocator::release(buffer, len, 1);
+        }
+        CStatus::Ok
+    });
+}
+
+/// Create a catalogue holding the messages built into the library
+///
+/// On success, `*catalogue` points to the new catalogue, which needs to be
+/// released with `catalogue_free()`. Catalogues are not allocated through
+/// the allocator set with `set_allocator()`.
+///
+/// # Safety
+///
+/// `catalogue` needs to point to writable memory for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn catalogue_default(catalogue: *mut *mut Catalogue) -> CStatus {
+    guard(|| {
+        if catalogue.is_null() {
+            return fail_with(CStatus::NullPointer, "null pointer argument");
+        }
+        *catalogue = Box::into_raw(Box::default());
+        CStatus::Ok
+    })
+}
+
+/// Load a catalogue from the TOML file at `path`
+///
+/// Works like `catalogue_default()`. If the file can't be read or holds an
+/// invalid catalogue, `InvalidCatalogue` is returned and `*catalogue` is set
+/// to NULL.
+///
+/// # Safety
+///
+/// `path` needs to be a NUL-terminated string, `catalogue` needs to point to
+/// writable memory for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn catalogue_load(path: *const c_char, catalogue: *mut *mut Catalogue) -> CStatus {
+    guard(|| {
+        if path.is_null() || catalogue.is_null() {
+            return fail_with(CStatus::NullPointer, "null pointer argument");
+        }
+        *catalogue = ptr::null_mut();
+
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(path) => path,
+            Err(e) => return fail_with(CStatus::BadUtf8, &format!("converting the path failed: {}", e)),
+        };
+        match Catalogue::load(path) {
+            Ok(loaded) => {
+                *catalogue = Box::into_raw(Box::new(loaded));
+                CStatus::Ok
+            },
+            Err(e) => fail_with(CStatus::InvalidCatalogue, &e.to_string()),
+        }
+    })
+}
+
+/// Build the response to `query` from `catalogue`
+///
+/// Unknown queries get the catalogue's fallback message. On success,
+/// `*response` points to a newly allocated package, which needs to be
+/// released with `free_package()`. On failure, `*response` is set to NULL.
+///
+/// # Safety
+///
+/// `catalogue` needs to point to a catalogue returned by `catalogue_default()`
+/// or `catalogue_load()`, `query` to a valid `CPackage`.
+#[no_mangle]
+pub unsafe extern "C" fn catalogue_answer(catalogue: *const Catalogue, query: *const CPackage,
+                                          response: *mut *mut CPackage) -> CStatus {
+    guard(|| {
+        if catalogue.is_null() || query.is_null() || response.is_null() {
+            return fail_with(CStatus::NullPointer, "null pointer argument");
+        }
+        *response = ptr::null_mut();
+
+        let query = match Package::from_c(&*query) {
+            Ok(query) => query,
+            Err(e) => return fail(&e),
+        };
+        let answer = (*catalogue).answer(query.id, query.query.as_deref(), query.version());
+        *response = package_into_c(answer);
+        if (*response).is_null() {
+            return fail_with(CStatus::OutOfMemory, "allocating the package failed");
+        }
+        CStatus::Ok
+    })
+}
+
+/// Release a catalogue returned by `catalogue_default()` or `catalogue_load()`
+///
+/// Packages returned by `catalogue_answer()` are independent of the
+/// catalogue and stay valid.
+///
+/// # Safety
+///
+/// `catalogue` must be NULL or a pointer returned by `catalogue_default()`
+/// or `catalogue_load()` that was not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn catalogue_free(catalogue: *mut Catalogue) {
+    guard(|| {
+        if !catalogue.is_null() {
+            drop(Box::from_raw(catalogue));
+        }
+        CStatus::Ok
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> String {
+        let message = last_error_message();
+        assert!(!message.is_null());
+        unsafe { CStr::from_ptr(message) }.to_string_lossy().into_owned()
+    }
+
+    fn decode(buffer: &[u8]) -> (CStatus, *mut CPackage) {
+        let mut package : *mut CPackage = ptr::null_mut();
+        let status = unsafe { decode_package(buffer.as_ptr(), buffer.len(), &mut package) };
+        (status, package)
+    }
+
+    #[test]
+    fn test_decode() {
+        let buffer = vec![
+            0x23, 0x42,  // ID
+            0b1100_1000, // response, bold, blink
+            0x12, 0x34, 0x56,
+            0x00, 0x02,  // len
+            0x48, 0x69,  // Hi
+            0x00, 0x00,  // len
+        ];
+
+        let (status, package) = decode(&buffer);
+        assert_eq!(status, CStatus::Ok);
+        assert!(!package.is_null());
+        {
+            let pkg = unsafe { &*package };
+            assert_eq!(pkg.id, 0x2342);
+            assert_eq!(pkg.style, STYLE_BOLD | STYLE_BLINK);
+            assert_eq!(pkg.query_len, 2);
+            assert_eq!(pkg.payload_len, 0);
+            assert!(pkg.payload.is_null());
+        }
+        unsafe { free_package(package) };
+    }
+
+    #[test]
+    fn test_extensions_passthrough() {
+        /// Decode and re-encode `buffer`, returning what C saw of the extensions
+        fn round_trip(buffer: &[u8]) -> (usize, bool) {
+            let (status, package) = decode(buffer);
+            assert_eq!(status, CStatus::Ok);
+            let extensions = unsafe { ((*package).extensions_len, (*package).extensions.is_null()) };
+
+            let mut encoded : *mut u8 = ptr::null_mut();
+            let mut len : usize = 0;
+            let status = unsafe { encode_package(package, &mut encoded, &mut len) };
+            assert_eq!(status, CStatus::Ok);
+            assert_eq!(unsafe { slice::from_raw_parts(encoded, len) }, buffer);
+
+            unsafe {
+                free_buffer(encoded, len);
+                free_package(package);
+            }
+            extensions
+        }
+
+        let buffer = vec![
+            0x23, 0x42,  // ID
+            0b0000_0100, // query, extensions
+            0x12, 0x34, 0x56,
+            0x00, 0x00,  // len
+            0x00, 0x00,  // len
+            0x00, 0x06,  // extension block len
+            0xbe, 0xef,  // kind: unknown
+            0x00, 0x02,  // len
+            0xab, 0xcd,
+        ];
+        assert_eq!(round_trip(&buffer), (6, false));
+
+        // An empty block is kept as non-NULL extensions without records
+        assert_eq!(round_trip(&[0x23, 0x42, 0x04, 0x12, 0x34, 0x56, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]), (0, false));
+        assert_eq!(round_trip(&[0x23, 0x42, 0x00, 0x12, 0x34, 0x56, 0x00, 0x00, 0x00, 0x00]), (0, true));
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let buffer = vec![
+            0x23, 0x42,  // ID
+            0b1100_1000, // response, bold, blink
+            0x12, 0x34, 0x56,
+            0x00, 0x05,  // len, but only two bytes follow
+            0x48, 0x69,
+        ];
+
+        for end in 0..buffer.len() {
+            let (status, package) = decode(&buffer[..end]);
+            assert_eq!(status, CStatus::Truncated);
+            assert!(package.is_null());
+        }
+        assert_eq!(decode(&buffer).0, CStatus::Truncated);
+        assert!(last_error().contains("query truncated at offset 8"));
+    }
+
+    #[test]
+    fn test_decode_bad_utf8() {
+        let buffer = vec![
+            0x23, 0x42,  // ID
+            0b0000_0000, // query
+            0x12, 0x34, 0x56,
+            0x00, 0x02,  // len
+            0xc3, 0x28,  // invalid two byte sequence
+            0x00, 0x00,  // len
+        ];
+
+        let (status, package) = decode(&buffer);
+        assert_eq!(status, CStatus::BadUtf8);
+        assert!(package.is_null());
+        assert!(last_error().contains("query is not valid utf-8"));
+    }
+
+    #[test]
+    fn test_decode_null() {
+        let mut package : *mut CPackage = ptr::null_mut();
+        let status = unsafe { decode_package(ptr::null(), 12, &mut package) };
+        assert_eq!(status, CStatus::NullPointer);
+
+        let buffer = [0u8; 10];
+        let status = unsafe { decode_package(buffer.as_ptr(), buffer.len(), ptr::null_mut()) };
+        assert_eq!(status, CStatus::NullPointer);
+    }
+
+    fn c_package(query: &mut [u8], payload: &mut [u8]) -> CPackage {
+        CPackage {
+            id: 0x2342,
+            message_type: 1,
+            style: STYLE_BOLD | STYLE_BLINK,
+            red: 0x12,
+            green: 0x34,
+            blue: 0x56,
+            query_len: query.len(),
+            query: query.as_mut_ptr(),
+            payload_len: payload.len(),
+            payload: payload.as_mut_ptr(),
+            extensions_len: 0,
+            extensions: ptr::null_mut(),
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut query = *b"Hi";
+        let c_pkg = c_package(&mut query, &mut []);
+        let mut buffer : *mut u8 = ptr::null_mut();
+        let mut len : usize = 0;
+
+        let status = unsafe { encode_package(&c_pkg, &mut buffer, &mut len) };
+        assert_eq!(status, CStatus::Ok);
+        assert_eq!(len, 12);
+        let encoded = unsafe { slice::from_raw_parts(buffer, len) };
+        assert_eq!(encoded, &[0x23, 0x42, 0b1100_1000, 0x12, 0x34, 0x56, 0x00, 0x02, 0x48, 0x69, 0x00, 0x00]);
+        unsafe { free_buffer(buffer, len) };
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let mut query = *b"Hi";
+        let c_pkg = c_package(&mut query, &mut []);
+        let expected = [0x23, 0x42, 0b1100_1000, 0x12, 0x34, 0x56, 0x00, 0x02, 0x48, 0x69, 0x00, 0x00];
+        let mut written : usize = 0;
+
+        // Ask for the size first
+        let status = unsafe { encode_package_into(&c_pkg, ptr::null_mut(), 0, &mut written) };
+        assert_eq!(status, CStatus::BufferTooSmall);
+        assert_eq!(written, expected.len());
+
+        let mut small = [0u8; 11];
+        let status = unsafe { encode_package_into(&c_pkg, small.as_mut_ptr(), small.len(), &mut written) };
+        assert_eq!(status, CStatus::BufferTooSmall);
+        assert_eq!(written, expected.len());
+        assert_eq!(small, [0u8; 11]);
+        assert!(last_error().contains("needs 12 bytes"));
+
+        let mut buffer = [0xffu8; 16];
+        let status = unsafe { encode_package_into(&c_pkg, buffer.as_mut_ptr(), buffer.len(), &mut written) };
+        assert_eq!(status, CStatus::Ok);
+        assert_eq!(written, expected.len());
+        assert_eq!(&buffer[..written], &expected);
+        assert_eq!(&buffer[written..], &[0xff; 4]);
+
+        let status = unsafe { encode_package_into(&c_pkg, buffer.as_mut_ptr(), buffer.len(), ptr::null_mut()) };
+        assert_eq!(status, CStatus::NullPointer);
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        let mut buffer : *mut u8 = ptr::null_mut();
+        let mut len : usize = 0;
+
+        let mut query = vec![b'a'; u16::MAX as usize + 1];
+        let c_pkg = c_package(&mut query, &mut []);
+        let status = unsafe { encode_package(&c_pkg, &mut buffer, &mut len) };
+        assert_eq!(status, CStatus::LengthOverflow);
+        assert!(last_error().contains("query is 65536 bytes long"));
+
+        let mut payload = [0xc3, 0x28];
+        let c_pkg = c_package(&mut [], &mut payload);
+        let status = unsafe { encode_package(&c_pkg, &mut buffer, &mut len) };
+        assert_eq!(status, CStatus::BadUtf8);
+        assert!(last_error().contains("payload is not valid utf-8"));
+
+        let mut c_pkg = c_package(&mut [], &mut []);
+        c_pkg.query_len = 2;
+        c_pkg.query = ptr::null_mut();
+        let status = unsafe { encode_package(&c_pkg, &mut buffer, &mut len) };
+        assert_eq!(status, CStatus::NullPointer);
+
+        let status = unsafe { encode_package(ptr::null(), &mut buffer, &mut len) };
+        assert_eq!(status, CStatus::NullPointer);
+        assert!(buffer.is_null());
+    }
+
+    #[test]
+    fn test_style_constants() {
+        assert_eq!(STYLE_BOLD, Style::BOLD.bits());
+        assert_eq!(STYLE_ITALIC, Style::ITALIC.bits());
+        assert_eq!(STYLE_UNDERLINED, Style::UNDERLINED.bits());
+        assert_eq!(STYLE_BLINK, Style::BLINK.bits());
+        assert_eq!(STYLE_RESERVED, Style::RESERVED.bits());
+    }
+
+    #[test]
+    fn test_parse_style() {
+        let mut style : u8 = 0;
+        let status = unsafe { parse_style(b"bold|blink\0".as_ptr() as *const c_char, &mut style) };
+        assert_eq!(status, CStatus::Ok);
+        assert_eq!(style, STYLE_BOLD | STYLE_BLINK);
+
+        let status = unsafe { parse_style(b"bold|shiny\0".as_ptr() as *const c_char, &mut style) };
+        assert_eq!(status, CStatus::InvalidStyle);
+        assert!(last_error().contains("shiny"));
+    }
+
+    #[test]
+    fn test_catalogue() {
+        let mut catalogue : *mut Catalogue = ptr::null_mut();
+        assert_eq!(unsafe { catalogue_default(&mut catalogue) }, CStatus::Ok);
+
+        let mut query = Package::new().set_id(0x2342).set_version(1);
+        query.set_query(Some(String::from("hamlet")));
+        let query = unsafe { package_into_c(query) };
+        let mut response : *mut CPackage = ptr::null_mut();
+        assert_eq!(unsafe { catalogue_answer(catalogue, query, &mut response) }, CStatus::Ok);
+        unsafe {
+            catalogue_free(catalogue);
+            free_package(query);
+        }
+
+        let answer = unsafe { Package::from_c(&*response) }.unwrap();
+        assert_eq!(answer.id, 0x2342);
+        assert_eq!(answer.message_type, MessageType::Response);
+        assert_eq!(answer.payload.as_deref(), Some("Alas, poor Yorick!"));
+        assert_eq!(answer.version(), Some(1));
+        unsafe { free_package(response) };
+
+        let path = CString::new("/nonexistent/messages.toml").unwrap();
+        let mut catalogue : *mut Catalogue = ptr::null_mut();
+        assert_eq!(unsafe { catalogue_load(path.as_ptr(), &mut catalogue) }, CStatus::InvalidCatalogue);
+        assert!(catalogue.is_null());
+        assert!(last_error().starts_with("reading the catalogue failed"));
+
+        let path = CString::new(concat!(env!("CARGO_MANIFEST_DIR"), "/messages.toml")).unwrap();
+        assert_eq!(unsafe { catalogue_load(path.as_ptr(), &mut catalogue) }, CStatus::Ok);
+        assert_eq!(unsafe { &*catalogue }, &Catalogue::default());
+        unsafe { catalogue_free(catalogue) };
+    }
+
+    #[test]
+    fn test_guard() {
+        let status = guard(|| panic!("at the disco"));
+        assert_eq!(status, CStatus::Panic);
+        assert!(last_error().contains("at the disco"));
+    }
+}
@@ -1,71 +1,110 @@
-#![recursion_limit = "1024"]
-use std::slice;
-
-/// Parser library for the SambaXP 2018 demo protocol
-///
-/// ```text
-/// The demo packets look like the following:
-///
-///                                    1  1  1  1  1  1
-///      0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5
-///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-///     |                      ID                       |
-///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-///     |QR|BD|IT|UL|BL|Reserved|         Red           |
-///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-///     |        Green          |         Blue          |
-///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-///     | Query ...                                     |
-///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-///     | ...                                           |
-///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-///     | Payload ...                                   |
-///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-///     | ...                                           |
-///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-///
-/// Where:
-///     ID      ID of the message requested
-///     QR      0 when query, 1 when response
-///     BD      1 when text should be bold
-///     IT      1 when text should be italic
-///     UL      1 when text should be underlined
-///     BL      1 when text should blink
-///     Red     u8 of red channel intensity
-///     Green   u8 of green channel intensity
-///     Blue    u8 of blue channel intensity
-///
-/// Both Query and Payload start with a u16 length value
-/// followed by a utf-8 encoded string.
-
-
-#[macro_use]
-extern crate error_chain;
+//! Parser library for the SambaXP 2018 demo protocol
+//!
+//! ```text
+//! The demo packets look like the following:
+//!
+//!                                    1  1  1  1  1  1
+//!      0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5
+//!     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+//!     |                      ID                       |
+//!     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+//!     |QR|BD|IT|UL|BL|EX|Rsvd |         Red           |
+//!     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+//!     |        Green          |         Blue          |
+//!     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+//!     | Query ...                                     |
+//!     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+//!     | ...                                           |
+//!     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+//!     | Payload ...                                   |
+//!     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+//!     | ...                                           |
+//!     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+//!     | Extensions ... (only if EX is set)            |
+//!     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+//!     | ...                                           |
+//!     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+//!
+//! Where:
+//!     ID      ID of the message requested
+//!     QR      0 when query, 1 when response
+//!     BD      1 when text should be bold
+//!     IT      1 when text should be italic
+//!     UL      1 when text should be underlined
+//!     BL      1 when text should blink
+//!     EX      1 when an extension block follows the payload
+//!     Rsvd    reserved for future use
+//!     Red     u8 of red channel intensity
+//!     Green   u8 of green channel intensity
+//!     Blue    u8 of blue channel intensity
+//!
+//! Both Query and Payload start with a u16 length value
+//! followed by a utf-8 encoded string.
+//!
+//! The extension block starts with a u16 length value, followed by
+//! extension records of a u16 kind, a u16 length and that many bytes
+//! of data. Packages without extension block are protocol version 0,
+//! later versions announce themselves with a VERSION extension.
+//! Responses to malformed queries carry an ERROR extension holding the
+//! error code.
+//!
+//! Datagram transports like UDP carry one package per datagram. On byte
+//! streams like TCP, each package is preceded by its length as a u32.
+//! ```
+
+use std::str;
 
 extern crate byteorder;
+extern crate serde;
+extern crate toml;
 
-mod errors {
-    error_chain! {}
-}
+#[cfg(feature = "tokio")]
+extern crate bytes;
+#[cfg(feature = "tokio")]
+extern crate tokio_util;
 
-mod codec;
+#[cfg(test)]
+extern crate proptest;
 
+mod allocator;
+mod catalogue;
+mod codec;
+mod errors;
+mod extension;
+mod ffi;
+mod stream;
+mod style;
+#[cfg(feature = "tokio")]
+mod tokio_codec;
+
+pub use allocator::CAllocator;
+pub use catalogue::{Catalogue, CatalogueError};
 pub use codec::*;
-use errors::*;
+pub use extension::{Extension, ExtensionRef, Extensions, ExtensionIter, PROTOCOL_VERSION};
+pub use ffi::*;
+pub use stream::{Decoded, Framing, StreamDecoder, MAX_DATAGRAM_LEN, MAX_PACKAGE_LEN};
+pub use style::{Style, StyleIter, ParseStyleError};
+#[cfg(feature = "tokio")]
+pub use tokio_codec::{CodecError, DatagramCodec, PackageCodec};
+pub use errors::Error;
+use errors::{Result, ResultExt};
 
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub struct Package {
     pub id: u16,
     pub message_type: MessageType,
-    pub bold: bool,
-    pub italic: bool,
-    pub underlined: bool,
-    pub blink: bool,
+    pub style: Style,
     pub red: u8,
     pub green: u8,
     pub blue: u8,
     pub query: Option<String>,
     pub payload: Option<String>,
+    pub extensions: Vec<Extension>,
+    /// Write an extension block even if `extensions` is empty
+    ///
+    /// Set when decoding a package whose EX bit announced an empty block,
+    /// so it encodes back to the same bytes.
+    pub empty_extension_block: bool,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
@@ -79,19 +118,28 @@ impl Default for Package {
         Package {
             id: 0,
             message_type: MessageType::Query,
-            bold: false,
-            italic: false,
-            underlined: false,
-            blink: false,
+            style: Style::empty(),
             red: 0,
             green: 0,
             blue: 0,
             query: None,
             payload: None,
+            extensions: Vec::new(),
+            empty_extension_block: false,
         }
     }
 }
 
+/// The EX bit of the flags byte
+const EXTENSION_FLAG : u8 = 0b0000_0100;
+
+fn check_len(field: &'static str, len: usize) -> Result<()> {
+    if len > u16::MAX as usize {
+        return Err(Error::LengthOverflow { field, len });
+    }
+    Ok(())
+}
+
 
 impl Package {
     /// Create a new `Package`
@@ -99,37 +147,6 @@ impl Package {
         Default::default()
     }
 
-    pub fn from(c_pkg: &CPackage) -> Self {
-        let msg_type : MessageType = if c_pkg.message_type == 0 {
-            MessageType::Query
-        } else {
-            MessageType::Response
-        };
-        let mut query : Option<String> = None;
-        if c_pkg.query_len > 0 {
-            let query_str = unsafe {::std::str::from_utf8(slice::from_raw_parts(c_pkg.query, c_pkg.query_len)).unwrap()};
-            query = Some(String::from(query_str));
-        };
-        let mut payload : Option<String> = None;
-        if c_pkg.payload_len > 0 {
-            let payload_str = unsafe {::std::str::from_utf8(slice::from_raw_parts(c_pkg.payload, c_pkg.payload_len)).unwrap()};
-            payload = Some(String::from(payload_str));
-        };
-        Package {
-            id: c_pkg.id,
-            message_type: msg_type,
-            bold: c_pkg.bold,
-            italic: c_pkg.italic,
-            underlined: c_pkg.underlined,
-            blink: c_pkg.blink,
-            red: c_pkg.red,
-            green: c_pkg.green,
-            blue: c_pkg.blue,
-            query,
-            payload,
-        }
-    }
-
     pub fn set_id(mut self, id: u16) -> Package {
         self.id = id;
         self
@@ -140,20 +157,25 @@ impl Package {
         self
     }
 
+    pub fn set_style(mut self, style: Style) -> Package {
+        self.style = style;
+        self
+    }
+
     pub fn set_bold(mut self, bold: bool) -> Package {
-        self.bold = bold;
+        self.style.set(Style::BOLD, bold);
         self
     }
     pub fn set_italic(mut self, italic: bool) -> Package {
-        self.italic = italic;
+        self.style.set(Style::ITALIC, italic);
         self
     }
     pub fn set_underlined(mut self, underlined: bool) -> Package {
-        self.underlined = underlined;
+        self.style.set(Style::UNDERLINED, underlined);
         self
     }
     pub fn set_blink(mut self, blink: bool) -> Package {
-        self.blink = blink;
+        self.style.set(Style::BLINK, blink);
         self
     }
 
@@ -165,108 +187,231 @@ impl Package {
     }
 
     pub fn set_query(&mut self, query: Option<String>){
-        self.query = match query {
-            None => None,
-            Some(q) => Some(String::from(q.as_str()))
-        };
+        self.query = query;
     }
 
     pub fn query_len(&self) -> usize {
         match self.query {
             None => 0,
-            Some(ref q) => q.as_bytes().len(),
+            Some(ref q) => q.len(),
         }
     }
 
     pub fn set_payload(mut self, payload: Option<String>) -> Package {
-        self.payload = match payload {
-            None => None,
-            Some(q) => Some(String::from(q.as_str()))
-        };
+        self.payload = payload;
         self
     }
 
     pub fn payload_len(&self) -> usize {
         match self.payload {
             None => 0,
-            Some(ref p) => p.as_bytes().len(),
+            Some(ref p) => p.len(),
+        }
+    }
+
+    /// Announce `version` as the protocol version of this package
+    pub fn set_version(mut self, version: u8) -> Package {
+        self.extensions.retain(|ext| ext.kind != Extension::VERSION);
+        self.extensions.push(Extension::version(version));
+        self
+    }
+
+    /// Exact number of bytes this package takes up on the wire
+    pub fn encoded_len(&self) -> usize {
+        // ID, flags, RGB and the two string lengths
+        let mut len = 10 + self.query_len() + self.payload_len();
+        if self.has_extension_block() {
+            len += 2 + extension::block_len(&self.extensions);
         }
+        len
+    }
+
+    /// Whether the EX bit is set and an extension block follows the payload
+    fn has_extension_block(&self) -> bool {
+        !self.extensions.is_empty() || self.empty_extension_block
+    }
+
+    /// Check that the package can be encoded
+    ///
+    /// Query, payload and each extension's data are prefixed with a u16
+    /// length, as is the extension block as a whole, so none of them may
+    /// be longer than 65535 bytes.
+    pub fn validate(&self) -> Result<()> {
+        check_len("query", self.query_len())?;
+        check_len("payload", self.payload_len())?;
+        for ext in &self.extensions {
+            check_len("extension data", ext.data.len())?;
+        }
+        check_len("extension block", extension::block_len(&self.extensions))?;
+        Ok(())
+    }
+
+    /// The protocol version announced by the sender, `None` for version 0 packages
+    pub fn version(&self) -> Option<u8> {
+        self.extension(Extension::VERSION).and_then(|data| data.first().cloned())
+    }
+
+    /// Data of the first extension of the given kind
+    pub fn extension(&self, kind: u16) -> Option<&[u8]> {
+        self.extensions.iter().find(|ext| ext.kind == kind).map(|ext| &ext.data[..])
+    }
+
+    /// Response to a query that failed to decode with `err`
+    ///
+    /// The payload describes the error for humans, the `ERROR` extension
+    /// carries `err.code()` for programs. `id` should be the query's ID if
+    /// it could be read at all.
+    pub fn error_response(id: u16, err: &Error) -> Package {
+        let mut package = Package::new().set_id(id)
+                                        .set_message_type(MessageType::Response)
+                                        .set_bold(true)
+                                        .set_rgb(0xff, 0x00, 0x00)
+                                        .set_payload(Some(err.to_string()));
+        package.extensions.push(Extension::new(Extension::ERROR, vec![err.code()]));
+        package
+    }
+
+    /// The error code if this is an error response, see `error_response()`
+    pub fn error_code(&self) -> Option<u8> {
+        self.extension(Extension::ERROR).and_then(|data| data.first().cloned())
     }
 }
 
-impl Serialisable<Package> for Package {
-    fn read(decoder: &mut Decoder) -> errors::Result<Self> {
-        let id = decoder.read_u16().chain_err(|| "reading ID failed")?;
+/// Borrowed view of a package
+///
+/// Query and payload point into the buffer the package was decoded from, so
+/// reading a `PackageRef` does not allocate. Use `Package::from()` to get an
+/// owned copy.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub struct PackageRef<'a> {
+    pub id: u16,
+    pub message_type: MessageType,
+    pub style: Style,
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub query: Option<&'a str>,
+    pub payload: Option<&'a str>,
+    pub extensions: Extensions<'a>,
+    /// The EX bit was set but the extension block was empty
+    pub empty_extension_block: bool,
+}
+
+impl<'a> PackageRef<'a> {
+    /// Decode a `PackageRef` borrowing from the decoder's buffer
+    pub fn read(decoder: &mut Decoder<'a>) -> Result<Self> {
+        let id = decoder.read_u16().field("ID")?;
 
         // Parse the bit flag field
-        let flags = decoder.read_u8().chain_err(|| "reading bitflags failed")?;
+        let flags_offset = decoder.position();
+        let flags = decoder.read_u8().field("flags")?;
         let message_type = if (0b1000_0000 & flags) == 0b1000_0000 {
             MessageType::Response
         } else {
             MessageType::Query
         };
-        let bold =       (0b0100_0000 & flags) == 0b0100_0000;
-        let italic =     (0b0010_0000 & flags) == 0b0010_0000;
-        let underlined = (0b0001_0000 & flags) == 0b0001_0000;
-        let blink =      (0b0000_1000 & flags) == 0b0000_1000;
-
-        let red = decoder.read_u8().chain_err(|| "reading red failed")?;
-        let green = decoder.read_u8().chain_err(|| "reading green failed")?;
-        let blue = decoder.read_u8().chain_err(|| "reading blue failed")?;
-
-        let len = decoder.read_u16().chain_err(|| "reading string length failed")?;
-        let query;
-        if len > 0 {
-            let raw_query = decoder.read_slice(len as usize).chain_err(|| "reading the query failed")?;
-            query = Some(String::from_utf8(raw_query.to_vec()).chain_err(|| "converting the query failed")?);
-        } else {
-            query = None;
+        let mut style = Style::from_bits(flags);
+        match decoder.mode() {
+            DecodeMode::Strict => {
+                if !style.reserved().is_empty() {
+                    return Err(Error::ReservedBits { offset: flags_offset, bits: style.reserved().bits() });
+                }
+            },
+            DecodeMode::Lenient => style.remove(Style::RESERVED),
+            DecodeMode::Preserve => {},
         }
 
-        let len = decoder.read_u16().chain_err(|| "reading string length failed")?;
-        let payload;
-        if len > 0 {
-            let raw_payload = decoder.read_slice(len as usize).chain_err(|| "reading the payload failed")?;
-            payload = Some(String::from_utf8(raw_payload.to_vec()).chain_err(|| "converting the payload failed")?);
+        let red = decoder.read_u8().field("red")?;
+        let green = decoder.read_u8().field("green")?;
+        let blue = decoder.read_u8().field("blue")?;
+
+        let len = decoder.read_u16().field("query length")?;
+        let query = if len > 0 {
+            Some(decoder.read_str(len as usize).field("query")?)
         } else {
-            payload = None;
-        }
+            None
+        };
 
-        Ok(Package{
+        let len = decoder.read_u16().field("payload length")?;
+        let payload = if len > 0 {
+            Some(decoder.read_str(len as usize).field("payload")?)
+        } else {
+            None
+        };
+
+        let has_block = flags & EXTENSION_FLAG == EXTENSION_FLAG;
+        let extensions = if has_block {
+            extension::read_block(decoder)?
+        } else {
+            Extensions::default()
+        };
+        let empty_extension_block = has_block && extensions.is_empty();
+
+        Ok(PackageRef{
             id,
             message_type,
-            bold,
-            italic,
-            underlined,
-            blink,
+            style,
             red,
             green,
             blue,
             query,
             payload,
+            extensions,
+            empty_extension_block,
         })
     }
 
-    fn write(&self, encoder: &mut Encoder) -> errors::Result<usize> {
-        encoder.write_u16(self.id).chain_err(|| "writing ID failed")?;
+    /// The protocol version announced by the sender, `None` for version 0 packages
+    pub fn version(&self) -> Option<u8> {
+        self.extensions.version()
+    }
+
+    /// The error code if this is an error response, see `Package::error_response()`
+    pub fn error_code(&self) -> Option<u8> {
+        self.extensions.error_code()
+    }
+}
+
+impl<'a> From<PackageRef<'a>> for Package {
+    fn from(pkg: PackageRef<'a>) -> Package {
+        Package {
+            id: pkg.id,
+            message_type: pkg.message_type,
+            style: pkg.style,
+            red: pkg.red,
+            green: pkg.green,
+            blue: pkg.blue,
+            query: pkg.query.map(String::from),
+            payload: pkg.payload.map(String::from),
+            extensions: pkg.extensions.iter().map(Extension::from).collect(),
+            empty_extension_block: pkg.empty_extension_block,
+        }
+    }
+}
+
+impl Serialisable<Package> for Package {
+    fn read(decoder: &mut Decoder) -> Result<Self> {
+        PackageRef::read(decoder).map(Package::from)
+    }
+
+    fn write(&self, encoder: &mut Encoder) -> Result<usize> {
+        // Don't write anything for packages that would end up corrupted
+        self.validate()?;
+        encoder.reserve(self.encoded_len());
+        let start = encoder.len();
+
+        encoder.write_u16(self.id)?;
 
-        let mut flags : u8 = match self.message_type {
+        let flags : u8 = match self.message_type {
             MessageType::Query => 0,
             MessageType::Response => 0b1000_0000,
+        } | self.style.bits();
+        let flags = if self.has_extension_block() {
+            flags | EXTENSION_FLAG
+        } else {
+            flags
         };
-        if self.bold {
-            flags |= 0b0100_0000;
-        }
-        if self.italic {
-            flags |= 0b0010_0000;
-        }
-        if self.underlined {
-            flags |= 0b0001_0000;
-        }
-        if self.blink {
-            flags |= 0b0000_1000;
-        }
-        encoder.write_u8(flags).chain_err(|| "writing bitflags failed")?;
+        encoder.write_u8(flags)?;
 
         encoder.write_u8(self.red)?;
         encoder.write_u8(self.green)?;
@@ -294,133 +439,19 @@ impl Serialisable<Package> for Package {
             },
         }
 
-        Ok(encoder.len())
-    }
-}
-
-
-#[repr(C)]
-pub struct CPackage {
-    pub id: u16,
-    pub message_type: u8,
-    pub bold: bool,
-    pub italic: bool,
-    pub underlined: bool,
-    pub blink: bool,
-    pub red: u8,
-    pub green: u8,
-    pub blue: u8,
-    pub query_len: usize,
-    pub query: *mut u8,
-    pub payload_len: usize,
-    pub payload: *mut u8,
-}
-
-
-use std::ptr;
-
-impl From<Package> for CPackage {
-    fn from(pkg: Package) -> CPackage {
-        let msg_type : u8 = match pkg.message_type {
-            MessageType::Query => 0,
-            MessageType::Response => 1
-        };
-        let mut q_len : usize = 0;
-        let mut q_ptr : *mut u8 = ptr::null_mut();
-        match pkg.query {
-            None => {},
-            Some(q) => {
-                q_len = q.len();
-                q_ptr = Box::into_raw(q.into_boxed_str()) as *mut u8;
-            },
-        };
-        let mut p_len : usize = 0;
-        let mut p_ptr : *mut u8 = ptr::null_mut();
-        match pkg.payload {
-            None => {},
-            Some(p) => {
-                p_len = p.len();
-                p_ptr = Box::into_raw(p.into_boxed_str()) as *mut u8;
-            },
-        };
-        CPackage {
-            id: pkg.id,
-            message_type: msg_type,
-            bold: pkg.bold,
-            italic: pkg.italic,
-            underlined: pkg.underlined,
-            blink: pkg.blink,
-            red: pkg.red,
-            green: pkg.green,
-            blue: pkg.blue,
-            query_len: q_len,
-            query: q_ptr,
-            payload_len: p_len,
-            payload: p_ptr,
+        if self.has_extension_block() {
+            extension::write_block(&self.extensions, encoder)?;
         }
-    }
-}
-
-#[no_mangle]
-pub extern "C" fn decode_package(buffer: *const u8, len: usize) -> *mut CPackage {
-    let buf : &[u8] = unsafe { slice::from_raw_parts(buffer, len) };
-    let mut decoder = Decoder::new(buf);
-    let pkg : CPackage = Package::read(&mut decoder).unwrap().into();
-    Box::into_raw(Box::new(pkg))
-}
-
-#[no_mangle]
-pub extern "C" fn encode_package(package: *const CPackage, buffer: *mut *mut u8, len: *mut usize) -> i32 {
-    if package.is_null() || len.is_null() {
-        return -1
-    }
 
-    let mut calculated_size : usize = 8;  // Size of the static fields
-    unsafe {
-        let pkg : &CPackage = &*package;
-        calculated_size += pkg.payload_len + pkg.query_len;
+        Ok(encoder.len() - start)
     }
 
-    let mut buf : Vec<u8> = Vec::with_capacity(calculated_size);
-    unsafe {
-        let c_pkg : &CPackage = &*package;
-        let pkg = Package::from(c_pkg);
-        let mut encoder = Encoder::new(&mut buf);
-        let written = pkg.write(&mut encoder).unwrap_or(0_usize);
-        if written == 0 {
-            println!("Gah, wie died!");
-            return -1
-        }
-        *len = written;
+    fn size_hint(&self) -> usize {
+        self.encoded_len()
     }
-    unsafe {
-        let buf_box = buf.into_boxed_slice();
-        *buffer = Box::into_raw(buf_box) as *mut u8;
-    }
-    0
 }
 
 
-#[no_mangle]
-pub extern "C" fn free_package(package: *mut CPackage) {
-    if !package.is_null() {
-        unsafe {
-            let _pkg = Box::from_raw(package);
-            // and drop it
-        }
-    }
-}
-
-#[no_mangle]
-pub extern "C" fn free_buffer(buffer: *mut u8) {
-    if !buffer.is_null() {
-        unsafe {
-            let _buf = Box::from_raw(buffer);
-            // and drop it
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -443,15 +474,14 @@ mod tests {
         let expected = Package{
             id: 0x2342,
             message_type: MessageType::Response,
-            bold: true,
-            italic: false,
-            underlined: false,
-            blink: true,
+            style: Style::BOLD | Style::BLINK,
             red: 0x12,
             green: 0x34,
             blue: 0x56,
             query: Some(String::from("Hi")),
             payload: None,
+            extensions: Vec::new(),
+            empty_extension_block: false,
         };
 
         let got = Package::read(&mut decoder).unwrap();
@@ -460,6 +490,230 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_read_ref() {
+        let buffer = vec![
+            0x23, 0x42,  // ID
+            0b0100_0000, // query, bold
+            0x12,
+            0x34,
+            0x56,
+            0x00, 0x02,  // len
+            0x48,        // H
+            0x69,        // i
+            0x00, 0x03,  // len
+            0x48,        // H
+            0x6f,        // o
+            0x21,        // !
+        ];
+
+        let mut decoder = Decoder::new(&buffer);
+
+        let got = PackageRef::read(&mut decoder).unwrap();
+
+        assert_eq!(got.id, 0x2342);
+        assert_eq!(got.message_type, MessageType::Query);
+        assert_eq!(got.style, Style::BOLD);
+        assert_eq!(got.query, Some("Hi"));
+        assert_eq!(got.payload, Some("Ho!"));
+
+        // No copies were made
+        assert_eq!(got.query.unwrap().as_ptr(), buffer[8..].as_ptr());
+        assert_eq!(got.payload.unwrap().as_ptr(), buffer[12..].as_ptr());
+
+        let owned = Package::from(got);
+        let mut expected = Package::new().set_id(0x2342).set_bold(true).set_rgb(0x12, 0x34, 0x56)
+                                         .set_payload(Some(String::from("Ho!")));
+        expected.set_query(Some(String::from("Hi")));
+        assert_eq!(owned, expected);
+    }
+
+    #[test]
+    fn test_reserved_bits() {
+        let buffer = vec![
+            0x23, 0x42,  // ID
+            0b0010_0011, // query, italic, reserved bits 0b11
+            0x12,
+            0x34,
+            0x56,
+            0x00, 0x00,  // len
+            0x00, 0x00,  // len
+        ];
+
+        let mut decoder = Decoder::new(&buffer);
+        let package = Package::read(&mut decoder).unwrap();
+        assert_eq!(package.style, Style::ITALIC | Style::from_bits(0b11));
+
+        let mut encoded: Vec<u8> = Vec::new();
+        package.write(&mut Encoder::new(&mut encoded)).unwrap();
+        assert_eq!(encoded, buffer);
+
+        let mut decoder = Decoder::with_mode(&buffer, DecodeMode::Lenient);
+        let package = Package::read(&mut decoder).unwrap();
+        assert_eq!(package.style, Style::ITALIC);
+
+        let mut decoder = Decoder::with_mode(&buffer, DecodeMode::Strict);
+        let err = Package::read(&mut decoder).unwrap_err();
+        assert_eq!(err, Error::ReservedBits { offset: 2, bits: 0b11 });
+
+        // Strict mode is fine with packages that don't use reserved bits
+        let mut clean = buffer.clone();
+        clean[2] = 0b0010_0000;
+        let mut decoder = Decoder::with_mode(&clean, DecodeMode::Strict);
+        assert_eq!(Package::read(&mut decoder).unwrap().style, Style::ITALIC);
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let buffer = vec![
+            0x23, 0x42,  // ID
+            0b0000_0000, // query
+            0x12,
+            0x34,
+            0x56,
+            0x00, 0x02,  // len
+            0x48,        // H
+            0x69,        // i
+            0x00, 0x03,  // len
+            0x48,        // H
+            0xc3, 0x28,  // invalid two byte sequence
+        ];
+
+        let mut decoder = Decoder::new(&buffer);
+        let err = Package::read(&mut decoder).unwrap_err();
+        assert_eq!(err.field(), "payload");
+        assert_eq!(err.offset(), Some(13));
+        match err {
+            Error::InvalidUtf8 { .. } => {},
+            ref err => panic!("unexpected error {:?}", err),
+        }
+
+        let mut decoder = Decoder::new(&buffer[..9]);
+        let err = Package::read(&mut decoder).unwrap_err();
+        assert_eq!(err, Error::Truncated { field: "query", offset: 8, needed: 2, available: 1 });
+
+        let mut decoder = Decoder::new(&buffer[..1]);
+        assert_eq!(Package::read(&mut decoder).unwrap_err().field(), "ID");
+    }
+
+    #[test]
+    fn test_error_response() {
+        let mut decoder = Decoder::new(&[0x23, 0x42, 0x00]);
+        let err = Package::read(&mut decoder).unwrap_err();
+        let response = Package::error_response(0x2342, &err);
+        assert_eq!(response.error_code(), Some(err.code()));
+        assert_eq!(response.payload, Some(err.to_string()));
+
+        let mut buffer: Vec<u8> = Vec::new();
+        response.write(&mut Encoder::new(&mut buffer)).unwrap();
+        let mut decoder = Decoder::new(&buffer);
+        let decoded = PackageRef::read(&mut decoder).unwrap();
+        assert_eq!(decoded.id, 0x2342);
+        assert_eq!(decoded.message_type, MessageType::Response);
+        assert_eq!(decoded.error_code(), Some(1));
+        assert_eq!(decoded.version(), None);
+
+        assert_eq!(Package::new().error_code(), None);
+    }
+
+    #[test]
+    fn test_extensions() {
+        let buffer = vec![
+            0x23, 0x42,  // ID
+            0b0000_0100, // query, extensions
+            0x12,
+            0x34,
+            0x56,
+            0x00, 0x02,  // len
+            0x48,        // H
+            0x69,        // i
+            0x00, 0x00,  // len
+            0x00, 0x0b,  // extension block len
+            0x00, 0x01,  // kind: version
+            0x00, 0x01,  // len
+            0x01,
+            0xbe, 0xef,  // kind: unknown
+            0x00, 0x02,  // len
+            0xab, 0xcd,
+        ];
+
+        let mut decoder = Decoder::new(&buffer);
+        let package = Package::read(&mut decoder).unwrap();
+        assert_eq!(package.style, Style::empty());
+        assert_eq!(package.version(), Some(1));
+        assert_eq!(package.extensions[1], Extension::new(0xbeef, vec![0xab, 0xcd]));
+
+        // Unknown extensions survive the round-trip
+        let mut encoded: Vec<u8> = Vec::new();
+        package.write(&mut Encoder::new(&mut encoded)).unwrap();
+        assert_eq!(encoded, buffer);
+
+        // Truncated extension block
+        let mut decoder = Decoder::new(&buffer[..buffer.len() - 1]);
+        let err = Package::read(&mut decoder).unwrap_err();
+        assert_eq!(err, Error::Truncated { field: "extension block", offset: 14, needed: 11, available: 10 });
+
+        // Packages without extensions are encoded exactly like version 0 packages
+        let mut package = package;
+        package.extensions.clear();
+        assert_eq!(package.version(), None);
+        let mut encoded: Vec<u8> = Vec::new();
+        package.write(&mut Encoder::new(&mut encoded)).unwrap();
+        assert_eq!(&encoded[..], &[0x23, 0x42, 0x00, 0x12, 0x34, 0x56, 0x00, 0x02, 0x48, 0x69, 0x00, 0x00]);
+
+        let package = package.set_version(1).set_version(PROTOCOL_VERSION);
+        assert_eq!(package.extensions, vec![Extension::version(PROTOCOL_VERSION)]);
+    }
+
+    #[test]
+    fn test_empty_extension_block() {
+        let buffer = vec![
+            0x23, 0x42,  // ID
+            0b0100_0100, // query, bold, extensions
+            0x12,
+            0x34,
+            0x56,
+            0x00, 0x00,  // len
+            0x00, 0x00,  // len
+            0x00, 0x00,  // extension block len
+        ];
+
+        let package = Package::read(&mut Decoder::new(&buffer)).unwrap();
+        assert!(package.extensions.is_empty());
+        assert!(package.empty_extension_block);
+        assert_eq!(package.version(), None);
+        assert_eq!(package.encoded_len(), buffer.len());
+
+        let mut encoded: Vec<u8> = Vec::new();
+        package.write(&mut Encoder::new(&mut encoded)).unwrap();
+        assert_eq!(encoded, buffer);
+
+        // Adding extensions to it needs no special care
+        let package = package.set_version(1);
+        assert_eq!(package.encoded_len(), buffer.len() + 5);
+    }
+
+    #[test]
+    fn test_length_limit() {
+        let mut package = Package::new().set_payload(Some("x".repeat(u16::MAX as usize)));
+        assert!(package.validate().is_ok());
+
+        package.set_query(Some("x".repeat(u16::MAX as usize + 1)));
+        let mut buffer: Vec<u8> = Vec::new();
+        let err = package.write(&mut Encoder::new(&mut buffer)).unwrap_err();
+        assert_eq!(err, Error::LengthOverflow { field: "query", len: 65536 });
+        assert!(buffer.is_empty());
+
+        package.set_query(None);
+        let package = package.set_payload(None);
+        let big = Extension::new(0x1234, vec![0; 40000]);
+        let package = Package { extensions: vec![big.clone()], ..package };
+        assert!(package.validate().is_ok());
+
+        let package = Package { extensions: vec![big.clone(), big], ..package };
+        assert_eq!(package.validate().unwrap_err().field(), "extension block");
+    }
+
     #[test]
     fn test_write() {
         let expected: Vec<u8> = vec![
@@ -477,15 +731,14 @@ mod tests {
         let package = Package {
             id: 0x2342,
             message_type: MessageType::Response,
-            bold: true,
-            italic: false,
-            underlined: false,
-            blink: true,
+            style: Style::BOLD | Style::BLINK,
             red: 0x12,
             green: 0x34,
             blue: 0x56,
             query: Some(String::from("Hi")),
             payload: None,
+            extensions: Vec::new(),
+            empty_extension_block: false,
         };
 
         let mut buffer: Vec<u8> = Vec::new();
@@ -497,4 +750,73 @@ mod tests {
         assert_eq!(*encoder.into_bytes(), expected);
 
     }
+
+    #[test]
+    fn test_encoded_len() {
+        let mut package = Package::new().set_payload(Some(String::from("Hello, world!")));
+        package.set_query(Some(String::from("greeting")));
+        assert_eq!(package.encoded_len(), 10 + 8 + 13);
+
+        let package = package.set_version(1);
+        assert_eq!(package.encoded_len(), 10 + 8 + 13 + 2 + 5);
+
+        // Only the package's own bytes are counted when appending to an encoder
+        let mut buffer: Vec<u8> = vec![0xff; 3];
+        let written = package.write(&mut Encoder::new(&mut buffer)).unwrap();
+        assert_eq!(written, package.encoded_len());
+        assert_eq!(buffer.len(), 3 + package.encoded_len());
+    }
+
+    use proptest::prelude::*;
+    use proptest::collection;
+
+    prop_compose! {
+        fn arb_extension()(kind in any::<u16>(),
+                           data in collection::vec(any::<u8>(), 0..64)) -> Extension {
+            Extension::new(kind, data)
+        }
+    }
+
+    prop_compose! {
+        pub fn arb_package()(id in any::<u16>(),
+                         response in any::<bool>(),
+                         style in any::<u8>(),
+                         (red, green, blue) in any::<(u8, u8, u8)>(),
+                         query in proptest::option::of(".{1,300}"),
+                         payload in proptest::option::of(".{1,300}"),
+                         extensions in collection::vec(arb_extension(), 0..4),
+                         empty_block in any::<bool>()) -> Package {
+            let message_type = if response { MessageType::Response } else { MessageType::Query };
+            Package {
+                id,
+                message_type,
+                style: Style::from_bits(style),
+                red,
+                green,
+                blue,
+                query,
+                payload,
+                empty_extension_block: empty_block && extensions.is_empty(),
+                extensions,
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_encoded_len(package in arb_package()) {
+            let mut buffer: Vec<u8> = Vec::new();
+            let written = package.write(&mut Encoder::new(&mut buffer)).unwrap();
+            prop_assert_eq!(written, package.encoded_len());
+            prop_assert_eq!(buffer.len(), package.size_hint());
+        }
+
+        #[test]
+        fn prop_roundtrip(package in arb_package()) {
+            let mut buffer: Vec<u8> = Vec::new();
+            package.write(&mut Encoder::new(&mut buffer)).unwrap();
+            let mut decoder = Decoder::new(&buffer);
+            prop_assert_eq!(Package::read(&mut decoder).unwrap(), package);
+        }
+    }
 }
@@ -0,0 +1,357 @@
+use byteorder::{ByteOrder, NetworkEndian};
+
+use codec::{DecodeMode, Decoder, Encoder, Serialisable};
+use errors::{Error, Result};
+use {Package, EXTENSION_FLAG};
+
+/// Largest package the wire format allows, with query, payload and
+/// extension block all at their u16 limit
+pub const MAX_PACKAGE_LEN : usize = 10 + 2 + 3 * u16::MAX as usize;
+
+/// Largest package a single datagram can carry, size receive buffers for
+/// datagram transports with this
+pub const MAX_DATAGRAM_LEN : usize = u16::MAX as usize;
+
+/// How packages are delimited on a byte stream
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Packages follow each other directly, their length fields tell
+    /// where each one ends
+    #[default]
+    Bare,
+    /// Each package is preceded by its length as a u32 in network byte
+    /// order, e.g. for TCP
+    LengthPrefixed,
+}
+
+/// Result of a `StreamDecoder::decode()` call
+#[derive(Clone, Debug, PartialEq)]
+pub enum Decoded {
+    /// A complete package
+    Package(Package),
+    /// At least this many more bytes are needed before the next package is complete
+    NeedMore(usize),
+}
+
+/// Incremental decoder for packages arriving on a byte stream
+///
+/// Bytes can be fed in chunks of any size, packages may be split across
+/// chunks or several packages may arrive in one chunk. A package is only
+/// decoded once all of its bytes are there, so a malformed package is
+/// reported once and skipped, the following packages decode just fine.
+#[derive(Clone, Debug, Default)]
+pub struct StreamDecoder {
+    buffer: Vec<u8>,
+    start: usize,
+    /// Length of the frame before `start` that `decode()` last handed out
+    last: usize,
+    mode: DecodeMode,
+    framing: Framing,
+}
+
+impl StreamDecoder {
+    /// Create a new `StreamDecoder`
+    pub fn new() -> Self {
+        StreamDecoder::with_mode(DecodeMode::default())
+    }
+
+    /// Create a new `StreamDecoder` using the given `DecodeMode`
+    pub fn with_mode(mode: DecodeMode) -> Self {
+        StreamDecoder {
+            buffer: Vec::new(),
+            start: 0,
+            last: 0,
+            mode,
+            framing: Framing::default(),
+        }
+    }
+
+    /// Expect packages delimited according to `framing`
+    pub fn set_framing(mut self, framing: Framing) -> StreamDecoder {
+        self.framing = framing;
+        self
+    }
+
+    /// Append `data` to the bytes waiting to be decoded
+    pub fn feed(&mut self, data: &[u8]) {
+        if self.start > 0 {
+            // Drop the packages already handed out before growing the buffer
+            self.buffer.drain(..self.start);
+            self.start = 0;
+            self.last = 0;
+        }
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Number of bytes fed but not decoded yet
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() - self.start
+    }
+
+    /// Decode the next package, if it is complete
+    ///
+    /// Errors are only returned for packages that are complete but
+    /// malformed. Their bytes are dropped, so calling `decode()` again
+    /// continues with the next package. The exception is a length prefix
+    /// larger than `MAX_PACKAGE_LEN`, after which the stream can't be
+    /// trusted any more and the error is returned on every call.
+    pub fn decode(&mut self) -> Result<Decoded> {
+        self.last = 0;
+        let pending = &self.buffer[self.start..];
+        let (header, len) = match next_frame(pending, self.framing)? {
+            Ok(frame) => frame,
+            Err(needed) => return Ok(Decoded::NeedMore(needed)),
+        };
+
+        self.start += header + len;
+        self.last = header + len;
+        read_frame(&pending[..header + len], header, self.mode).map(Decoded::Package)
+    }
+
+    /// Raw bytes of the frame the last `decode()` call returned a package or
+    /// an error for, framing included
+    ///
+    /// Empty if that call found no complete frame, and once more bytes are fed.
+    pub fn last_frame(&self) -> &[u8] {
+        &self.buffer[self.start - self.last..self.start]
+    }
+}
+
+/// Yields packages until more bytes are needed
+impl Iterator for StreamDecoder {
+    type Item = Result<Package>;
+
+    fn next(&mut self) -> Option<Result<Package>> {
+        match self.decode() {
+            Ok(Decoded::Package(package)) => Some(Ok(package)),
+            Ok(Decoded::NeedMore(_)) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Package {
+    /// Write the package preceded by its length, for `Framing::LengthPrefixed`
+    pub fn write_framed(&self, encoder: &mut Encoder) -> Result<usize> {
+        self.validate()?;
+        let written = encoder.write_u32(self.encoded_len() as u32)?;
+        Ok(written + self.write(encoder)?)
+    }
+}
+
+/// Find the next frame at the start of `buffer`
+///
+/// Returns the length of the frame header and of the package following it,
+/// or how many more bytes are needed at least to tell.
+pub(crate) fn next_frame(buffer: &[u8], framing: Framing) -> Result<::std::result::Result<(usize, usize), usize>> {
+    Ok(match framing {
+        Framing::Bare => frame_len(buffer).map(|len| (0, len)),
+        Framing::LengthPrefixed => prefixed_frame_len(buffer)?.map(|len| (4, len)),
+    })
+}
+
+/// Decode the package in `frame`, after a header of `header` bytes
+pub(crate) fn read_frame(frame: &[u8], header: usize, mode: DecodeMode) -> Result<Package> {
+    let mut decoder = Decoder::with_mode(&frame[header..], mode);
+    // Report offsets relative to the frame, header included
+    Package::read(&mut decoder).map_err(|e| e.shift(header))
+}
+
+/// Length of the package following the u32 length prefix at the start of `buffer`
+fn prefixed_frame_len(buffer: &[u8]) -> Result<::std::result::Result<usize, usize>> {
+    if buffer.len() < 4 {
+        return Ok(Err(4 - buffer.len()));
+    }
+    let len = NetworkEndian::read_u32(buffer) as usize;
+    if len > MAX_PACKAGE_LEN {
+        return Err(Error::FrameTooLong { len });
+    }
+    if buffer.len() < 4 + len {
+        return Ok(Err(4 + len - buffer.len()));
+    }
+    Ok(Ok(len))
+}
+
+/// Length of the package at the start of `buffer`
+///
+/// Only the length fields are looked at. If `buffer` is too short to tell,
+/// returns how many more bytes are needed at least.
+fn frame_len(buffer: &[u8]) -> ::std::result::Result<usize, usize> {
+    let need = |len: usize| {
+        if buffer.len() < len {
+            Err(len - buffer.len())
+        } else {
+            Ok(())
+        }
+    };
+    let read_len = |offset: usize| NetworkEndian::read_u16(&buffer[offset..]) as usize;
+
+    // ID, flags, RGB and the query length
+    let mut len = 8;
+    need(len)?;
+    len += read_len(len - 2) + 2;
+    need(len)?;
+    len += read_len(len - 2);
+    if buffer[2] & EXTENSION_FLAG == EXTENSION_FLAG {
+        len += 2;
+        need(len)?;
+        len += read_len(len - 2);
+    }
+    need(len)?;
+    Ok(len)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::Encoder;
+    use errors::Error;
+    use tests::arb_package;
+    use proptest::prelude::*;
+    use proptest::collection;
+
+    const PACKAGE: [u8; 19] = [
+        0x23, 0x42,  // ID
+        0b0100_0100, // query, bold, extensions
+        0x12, 0x34, 0x56,
+        0x00, 0x02,  // len
+        0x48, 0x69,  // Hi
+        0x00, 0x00,  // len
+        0x00, 0x05,  // extension block len
+        0x00, 0x01,  // kind: version
+        0x00, 0x01,  // len
+        0x01,
+    ];
+
+    #[test]
+    fn test_need_more() {
+        let mut stream = StreamDecoder::new();
+        assert_eq!(stream.decode().unwrap(), Decoded::NeedMore(8));
+
+        // Each length field tells how much more is needed
+        let expected = [8, 7, 6, 5, 4, 3, 2, 1, 4, 3, 2, 1, 2, 1, 5, 4, 3, 2, 1];
+        for (i, &byte) in PACKAGE.iter().enumerate() {
+            assert_eq!(stream.decode().unwrap(), Decoded::NeedMore(expected[i]));
+            stream.feed(&[byte]);
+        }
+
+        match stream.decode().unwrap() {
+            Decoded::Package(package) => {
+                assert_eq!(package.query, Some(String::from("Hi")));
+                assert_eq!(package.version(), Some(1));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(stream.buffered(), 0);
+        assert_eq!(stream.decode().unwrap(), Decoded::NeedMore(8));
+    }
+
+    #[test]
+    fn test_concatenated() {
+        let mut stream = StreamDecoder::new();
+        let mut data = PACKAGE.to_vec();
+        data.extend_from_slice(&PACKAGE);
+        data.extend_from_slice(&PACKAGE[..4]);
+        stream.feed(&data);
+
+        let packages : Vec<Package> = stream.by_ref().map(|p| p.unwrap()).collect();
+        assert_eq!(packages.len(), 2);
+        assert_eq!(stream.buffered(), 4);
+
+        stream.feed(&PACKAGE[4..]);
+        assert_eq!(stream.count(), 1);
+    }
+
+    #[test]
+    fn test_malformed_is_skipped() {
+        let mut broken = PACKAGE;
+        broken[8] = 0xc3;  // invalid utf-8 in the query
+
+        let mut stream = StreamDecoder::new();
+        stream.feed(&broken);
+        stream.feed(&PACKAGE);
+        match stream.decode().unwrap_err() {
+            Error::InvalidUtf8 { field, offset, .. } => {
+                assert_eq!(field, "query");
+                assert_eq!(offset, 8);
+            },
+            err => panic!("unexpected error {:?}", err),
+        }
+        assert!(stream.next().unwrap().is_ok());
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        let mut reserved = PACKAGE;
+        reserved[2] |= 0b11;
+
+        let mut stream = StreamDecoder::with_mode(DecodeMode::Strict);
+        stream.feed(&reserved);
+        assert_eq!(stream.decode().unwrap_err(), Error::ReservedBits { offset: 2, bits: 0b11 });
+    }
+
+    #[test]
+    fn test_length_prefixed() {
+        let package = Package::new().set_id(0x2342).set_version(1);
+        let mut data: Vec<u8> = Vec::new();
+        let written = package.write_framed(&mut Encoder::new(&mut data)).unwrap();
+        assert_eq!(written, 4 + package.encoded_len());
+        assert_eq!(&data[..4], &[0x00, 0x00, 0x00, 0x11]);
+
+        let mut stream = StreamDecoder::new().set_framing(Framing::LengthPrefixed);
+        assert_eq!(stream.decode().unwrap(), Decoded::NeedMore(4));
+        stream.feed(&data[..6]);
+        assert_eq!(stream.decode().unwrap(), Decoded::NeedMore(data.len() - 6));
+        assert!(stream.last_frame().is_empty());
+        stream.feed(&data[6..]);
+        assert_eq!(stream.decode().unwrap(), Decoded::Package(package));
+        assert_eq!(stream.last_frame(), &data[..]);
+
+        // Offsets count the length prefix as well
+        let mut broken = vec![0x00, 0x00, 0x00, 0x0b];
+        broken.extend_from_slice(&PACKAGE[..11]);
+        stream.feed(&broken);
+        assert_eq!(stream.decode().unwrap_err(),
+                   Error::Truncated { field: "payload length", offset: 14, needed: 2, available: 1 });
+        assert_eq!(stream.buffered(), 0);
+        assert_eq!(stream.last_frame(), &broken[..]);
+
+        stream.feed(&[0x7f, 0xff, 0xff, 0xff]);
+        assert_eq!(stream.decode().unwrap_err(), Error::FrameTooLong { len: 0x7fff_ffff });
+        assert!(stream.decode().is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_chunked(packages in collection::vec(arb_package(), 1..5),
+                        chunks in collection::vec(1usize..64, 1..32),
+                        prefixed in any::<bool>()) {
+            let mut data: Vec<u8> = Vec::new();
+            for package in &packages {
+                if prefixed {
+                    package.write_framed(&mut Encoder::new(&mut data)).unwrap();
+                } else {
+                    package.write(&mut Encoder::new(&mut data)).unwrap();
+                }
+            }
+
+            let framing = if prefixed { Framing::LengthPrefixed } else { Framing::Bare };
+            let mut stream = StreamDecoder::new().set_framing(framing);
+            let mut decoded = Vec::new();
+            let mut rest = &data[..];
+            let mut sizes = chunks.iter().cycle();
+            while !rest.is_empty() {
+                let size = (*sizes.next().unwrap()).min(rest.len());
+                stream.feed(&rest[..size]);
+                rest = &rest[size..];
+                for package in stream.by_ref() {
+                    decoded.push(package.unwrap());
+                }
+            }
+            prop_assert_eq!(decoded, packages);
+            prop_assert_eq!(stream.buffered(), 0);
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use std::error;
+use std::fmt;
+use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign, Not, Sub, SubAssign};
+use std::str::FromStr;
+
+/// Text style of a package
+///
+/// Holds the BD, IT, UL and BL bits of the flags byte. The reserved bits are
+/// kept as well, so a package survives a decode/encode round-trip unchanged.
+/// The EX bit is not part of the style, it is derived from the extensions.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Style(u8);
+
+/// All bits of the flags byte a `Style` can hold, everything but QR and EX
+const STYLE_MASK : u8 = 0b0111_1011;
+
+/// Named flags in wire order, used for iteration, display and parsing
+const NAMED : [(Style, &str); 4] = [
+    (Style::BOLD, "bold"),
+    (Style::ITALIC, "italic"),
+    (Style::UNDERLINED, "underlined"),
+    (Style::BLINK, "blink"),
+];
+
+impl Style {
+    pub const BOLD: Style = Style(0b0100_0000);
+    pub const ITALIC: Style = Style(0b0010_0000);
+    pub const UNDERLINED: Style = Style(0b0001_0000);
+    pub const BLINK: Style = Style(0b0000_1000);
+    /// The bits marked "Reserved" in the protocol
+    pub const RESERVED: Style = Style(0b0000_0011);
+
+    /// A style without any flags set
+    pub fn empty() -> Self {
+        Style(0)
+    }
+
+    /// A style with all named flags set
+    pub fn all() -> Self {
+        Style::BOLD | Style::ITALIC | Style::UNDERLINED | Style::BLINK
+    }
+
+    /// Create a `Style` from the flags byte, ignoring the QR and EX bits
+    pub fn from_bits(bits: u8) -> Self {
+        Style(bits & STYLE_MASK)
+    }
+
+    /// The raw bits as they go into the flags byte
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Just the reserved bits of this style
+    pub fn reserved(self) -> Style {
+        self & Style::RESERVED
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Check if all flags in `other` are set
+    pub fn contains(self, other: Style) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Check if any flag in `other` is set
+    pub fn intersects(self, other: Style) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn insert(&mut self, other: Style) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Style) {
+        self.0 &= !other.0;
+    }
+
+    pub fn toggle(&mut self, other: Style) {
+        self.0 ^= other.0;
+    }
+
+    /// Insert or remove `other`, depending on `value`
+    pub fn set(&mut self, other: Style, value: bool) {
+        if value {
+            self.insert(other);
+        } else {
+            self.remove(other);
+        }
+    }
+
+    /// Iterate over the named flags that are set, in wire order
+    ///
+    /// Reserved bits are not included.
+    pub fn iter(self) -> StyleIter {
+        StyleIter { style: self, index: 0 }
+    }
+}
+
+impl BitOr for Style {
+    type Output = Style;
+    fn bitor(self, other: Style) -> Style {
+        Style(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for Style {
+    fn bitor_assign(&mut self, other: Style) {
+        self.0 |= other.0;
+    }
+}
+
+impl BitAnd for Style {
+    type Output = Style;
+    fn bitand(self, other: Style) -> Style {
+        Style(self.0 & other.0)
+    }
+}
+
+impl BitAndAssign for Style {
+    fn bitand_assign(&mut self, other: Style) {
+        self.0 &= other.0;
+    }
+}
+
+impl BitXor for Style {
+    type Output = Style;
+    fn bitxor(self, other: Style) -> Style {
+        Style(self.0 ^ other.0)
+    }
+}
+
+impl BitXorAssign for Style {
+    fn bitxor_assign(&mut self, other: Style) {
+        self.0 ^= other.0;
+    }
+}
+
+impl Sub for Style {
+    type Output = Style;
+    fn sub(self, other: Style) -> Style {
+        Style(self.0 & !other.0)
+    }
+}
+
+impl SubAssign for Style {
+    fn sub_assign(&mut self, other: Style) {
+        self.0 &= !other.0;
+    }
+}
+
+impl Not for Style {
+    type Output = Style;
+    fn not(self) -> Style {
+        Style(!self.0 & STYLE_MASK)
+    }
+}
+
+/// Iterator over the named flags of a `Style`
+pub struct StyleIter {
+    style: Style,
+    index: usize,
+}
+
+impl Iterator for StyleIter {
+    type Item = Style;
+
+    fn next(&mut self) -> Option<Style> {
+        while self.index < NAMED.len() {
+            let (flag, _) = NAMED[self.index];
+            self.index += 1;
+            if self.style.contains(flag) {
+                return Some(flag);
+            }
+        }
+        None
+    }
+}
+
+impl IntoIterator for Style {
+    type Item = Style;
+    type IntoIter = StyleIter;
+
+    fn into_iter(self) -> StyleIter {
+        self.iter()
+    }
+}
+
+/// Formats as e.g. `bold|blink`, with reserved bits appended in hex
+/// and `none` for the empty style
+impl fmt::Display for Style {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+
+        let mut first = true;
+        for (flag, name) in NAMED.iter() {
+            if self.contains(*flag) {
+                if !first {
+                    write!(f, "|")?;
+                }
+                write!(f, "{}", name)?;
+                first = false;
+            }
+        }
+
+        let reserved = self.reserved();
+        if !reserved.is_empty() {
+            if !first {
+                write!(f, "|")?;
+            }
+            write!(f, "{:#04x}", reserved.bits())?;
+        }
+        Ok(())
+    }
+}
+
+/// Error returned when parsing a `Style` from a string fails
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseStyleError {
+    flag: String,
+}
+
+impl fmt::Display for ParseStyleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown style flag '{}'", self.flag)
+    }
+}
+
+impl error::Error for ParseStyleError {}
+
+/// Parses the `Display` format, names are case-insensitive
+impl FromStr for Style {
+    type Err = ParseStyleError;
+
+    fn from_str(s: &str) -> Result<Style, ParseStyleError> {
+        let mut style = Style::empty();
+        let s = s.trim();
+        if s.is_empty() || s.eq_ignore_ascii_case("none") {
+            return Ok(style);
+        }
+
+        for part in s.split('|').map(str::trim) {
+            let named = NAMED.iter().find(|&&(_, name)| name.eq_ignore_ascii_case(part));
+            if let Some(&(flag, _)) = named {
+                style.insert(flag);
+                continue;
+            }
+
+            let hex = part.strip_prefix("0x").or_else(|| part.strip_prefix("0X"));
+            match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                Some(bits) if bits & !STYLE_MASK == 0 => style.insert(Style(bits)),
+                _ => return Err(ParseStyleError { flag: String::from(part) }),
+            }
+        }
+        Ok(style)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_operations() {
+        let mut style = Style::BOLD | Style::BLINK;
+        assert!(style.contains(Style::BOLD));
+        assert!(!style.contains(Style::BOLD | Style::ITALIC));
+        assert!(style.intersects(Style::BOLD | Style::ITALIC));
+
+        style.insert(Style::ITALIC);
+        style.remove(Style::BOLD);
+        assert_eq!(style, Style::ITALIC | Style::BLINK);
+
+        style.toggle(Style::BLINK);
+        style.set(Style::UNDERLINED, true);
+        assert_eq!(style, Style::ITALIC | Style::UNDERLINED);
+
+        assert_eq!(Style::all() - style, Style::BOLD | Style::BLINK);
+        assert_eq!(!Style::all(), Style::RESERVED);
+        assert_eq!(Style::from_bits(0xff), Style::all() | Style::RESERVED);
+    }
+
+    #[test]
+    fn test_iter() {
+        let flags : Vec<Style> = (Style::BLINK | Style::BOLD | Style::RESERVED).iter().collect();
+        assert_eq!(flags, vec![Style::BOLD, Style::BLINK]);
+        assert_eq!(Style::empty().iter().count(), 0);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Style::empty().to_string(), "none");
+        assert_eq!((Style::BLINK | Style::BOLD).to_string(), "bold|blink");
+        assert_eq!((Style::ITALIC | Style::from_bits(0b11)).to_string(), "italic|0x03");
+        assert_eq!(Style::from_bits(0b001).to_string(), "0x01");
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("bold|blink".parse(), Ok(Style::BOLD | Style::BLINK));
+        assert_eq!(" Italic | UNDERLINED ".parse(), Ok(Style::ITALIC | Style::UNDERLINED));
+        assert_eq!("none".parse(), Ok(Style::empty()));
+        assert_eq!("".parse(), Ok(Style::empty()));
+        assert_eq!("bold|0x03".parse(), Ok(Style::BOLD | Style::from_bits(0b011)));
+        assert!("bold|shiny".parse::<Style>().is_err());
+        assert!("0x80".parse::<Style>().is_err());
+        assert!("0x04".parse::<Style>().is_err());
+
+        for bits in 0..0x80 {
+            let style = Style::from_bits(bits);
+            assert_eq!(style.to_string().parse(), Ok(style));
+        }
+    }
+}
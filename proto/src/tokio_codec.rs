@@ -0,0 +1,240 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec;
+
+use codec::{DecodeMode, Encoder, Serialisable};
+use errors::Error;
+use stream::{next_frame, read_frame, Framing};
+use Package;
+
+/// Errors returned by `PackageCodec` and `DatagramCodec`
+#[derive(Debug)]
+pub enum CodecError {
+    /// Reading from or writing to the underlying transport failed
+    Io(io::Error),
+    /// A package could not be decoded or encoded
+    Package(Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CodecError::Io(ref e) => write!(f, "i/o error: {}", e),
+            CodecError::Package(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            CodecError::Io(ref e) => Some(e),
+            CodecError::Package(ref e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for CodecError {
+    fn from(err: io::Error) -> CodecError {
+        CodecError::Io(err)
+    }
+}
+
+impl From<Error> for CodecError {
+    fn from(err: Error) -> CodecError {
+        CodecError::Package(err)
+    }
+}
+
+fn encode_into(package: &Package, framing: Framing, dst: &mut BytesMut) -> Result<(), CodecError> {
+    let mut buffer: Vec<u8> = Vec::with_capacity(4 + package.encoded_len());
+    {
+        let mut encoder = Encoder::new(&mut buffer);
+        match framing {
+            Framing::Bare => package.write(&mut encoder)?,
+            Framing::LengthPrefixed => package.write_framed(&mut encoder)?,
+        };
+    }
+    dst.extend_from_slice(&buffer);
+    Ok(())
+}
+
+/// Codec for packages on a byte stream, for use with `Framed`
+///
+/// Uses `Framing::LengthPrefixed` like the TCP transport unless told
+/// otherwise. A malformed package ends the stream, as `FramedRead` stops
+/// after the first error.
+#[derive(Copy, Clone, Debug)]
+pub struct PackageCodec {
+    mode: DecodeMode,
+    framing: Framing,
+}
+
+impl PackageCodec {
+    /// Create a new `PackageCodec`
+    pub fn new() -> Self {
+        PackageCodec {
+            mode: DecodeMode::default(),
+            framing: Framing::LengthPrefixed,
+        }
+    }
+
+    pub fn set_mode(mut self, mode: DecodeMode) -> PackageCodec {
+        self.mode = mode;
+        self
+    }
+
+    pub fn set_framing(mut self, framing: Framing) -> PackageCodec {
+        self.framing = framing;
+        self
+    }
+}
+
+impl Default for PackageCodec {
+    fn default() -> Self {
+        PackageCodec::new()
+    }
+}
+
+impl codec::Decoder for PackageCodec {
+    type Item = Package;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Package>, CodecError> {
+        let (header, len) = match next_frame(src, self.framing)? {
+            Ok(frame) => frame,
+            Err(needed) => {
+                src.reserve(needed);
+                return Ok(None);
+            },
+        };
+
+        let frame = src.split_to(header + len);
+        Ok(Some(read_frame(&frame, header, self.mode)?))
+    }
+}
+
+impl codec::Encoder<Package> for PackageCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, package: Package, dst: &mut BytesMut) -> Result<(), CodecError> {
+        encode_into(&package, self.framing, dst)
+    }
+}
+
+/// Codec for one package per datagram, for use with `UdpFramed`
+///
+/// Unlike `PackageCodec`, a malformed datagram is reported and dropped, the
+/// following datagrams are decoded as usual.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DatagramCodec {
+    mode: DecodeMode,
+}
+
+impl DatagramCodec {
+    /// Create a new `DatagramCodec`
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn set_mode(mut self, mode: DecodeMode) -> DatagramCodec {
+        self.mode = mode;
+        self
+    }
+}
+
+impl codec::Decoder for DatagramCodec {
+    type Item = Package;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Package>, CodecError> {
+        if !src.has_remaining() {
+            return Ok(None);
+        }
+        // Consume the whole datagram up front, so a broken one isn't retried
+        let datagram = src.split();
+        Ok(Some(read_frame(&datagram, 0, self.mode)?))
+    }
+}
+
+impl codec::Encoder<Package> for DatagramCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, package: Package, dst: &mut BytesMut) -> Result<(), CodecError> {
+        encode_into(&package, Framing::Bare, dst)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn package() -> Package {
+        let mut package = Package::new().set_id(0x2342).set_bold(true).set_version(1)
+                                        .set_payload(Some(String::from("Hello")));
+        package.set_query(Some(String::from("greeting")));
+        package
+    }
+
+    #[test]
+    fn test_stream_roundtrip() {
+        let mut codec = PackageCodec::new();
+        let mut buffer = BytesMut::new();
+        codec.encode(package(), &mut buffer).unwrap();
+        codec.encode(package().set_id(1), &mut buffer).unwrap();
+        assert_eq!(buffer.len(), 2 * (4 + package().encoded_len()));
+
+        // Partial frames wait for more data
+        let mut partial = buffer.split_to(10);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buffer);
+        let mut buffer = partial;
+
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(package()));
+        assert_eq!(codec.decode(&mut buffer).unwrap().unwrap().id, 1);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_bare_framing() {
+        let mut codec = PackageCodec::new().set_framing(Framing::Bare);
+        let mut buffer = BytesMut::new();
+        codec.encode(package(), &mut buffer).unwrap();
+        assert_eq!(buffer.len(), package().encoded_len());
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(package()));
+    }
+
+    #[test]
+    fn test_datagram() {
+        let mut codec = DatagramCodec::new();
+        let mut buffer = BytesMut::new();
+        codec.encode(package(), &mut buffer).unwrap();
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(package()));
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+
+        // Broken datagrams are consumed along with the error
+        let mut buffer = BytesMut::from(&[0x23, 0x42, 0x00][..]);
+        match codec.decode(&mut buffer) {
+            Err(CodecError::Package(Error::Truncated { field, .. })) => assert_eq!(field, "red"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        let big = Package::new().set_payload(Some("x".repeat(u16::MAX as usize + 1)));
+        let mut buffer = BytesMut::new();
+        match PackageCodec::new().encode(big, &mut buffer) {
+            Err(CodecError::Package(Error::LengthOverflow { field, .. })) => assert_eq!(field, "payload"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+//! Check that memory handed out through the C API comes from the allocator
+//! set with `set_allocator()`. The allocator is global, so this lives in its
+//! own test binary.
+
+extern crate fancy_talk;
+
+use std::alloc::{self, Layout};
+use std::os::raw::c_void;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use fancy_talk::*;
+
+/// Allocation bookkeeping passed to the hooks as their context
+struct Arena {
+    live: AtomicUsize,
+    fail: AtomicBool,
+}
+
+/// Room for the allocation size in front of each allocation, keeping the
+/// same alignment malloc() would give
+const HEADER: usize = 16;
+
+unsafe extern "C" fn arena_alloc(context: *mut c_void, size: usize) -> *mut c_void {
+    let arena = &*(context as *const Arena);
+    if arena.fail.load(Ordering::SeqCst) {
+        return ptr::null_mut();
+    }
+    let block = alloc::alloc(Layout::from_size_align(HEADER + size, HEADER).unwrap());
+    if block.is_null() {
+        return ptr::null_mut();
+    }
+    *(block as *mut usize) = size;
+    arena.live.fetch_add(1, Ordering::SeqCst);
+    block.add(HEADER) as *mut c_void
+}
+
+unsafe extern "C" fn arena_free(context: *mut c_void, ptr: *mut c_void) {
+    let arena = &*(context as *const Arena);
+    let block = (ptr as *mut u8).sub(HEADER);
+    let size = *(block as *mut usize);
+    alloc::dealloc(block, Layout::from_size_align(HEADER + size, HEADER).unwrap());
+    arena.live.fetch_sub(1, Ordering::SeqCst);
+}
+
+const PACKAGE: [u8; 25] = [
+    0x23, 0x42,  // ID
+    0b0100_0100, // query, bold, extensions
+    0x12, 0x34, 0x56,
+    0x00, 0x05,  // len
+    b'h', b'e', b'l', b'l', b'o',
+    0x00, 0x01,  // len
+    b'!',
+    0x00, 0x05,  // extension block len
+    0x00, 0x01,  // kind: version
+    0x00, 0x01,  // len
+    0x01,
+    0x00, 0x00,  // padding, not part of the package
+];
+
+const PACKAGE_LEN: usize = 23;
+
+#[test]
+fn test_allocator_hooks() {
+    let arena = Arena { live: AtomicUsize::new(0), fail: AtomicBool::new(false) };
+    let hooks = CAllocator {
+        context: &arena as *const Arena as *mut c_void,
+        alloc: Some(arena_alloc),
+        free: Some(arena_free),
+    };
+    let live = || arena.live.load(Ordering::SeqCst);
+
+    unsafe {
+        let incomplete = CAllocator { free: None, ..hooks };
+        assert_eq!(set_allocator(&incomplete), CStatus::NullPointer);
+        assert_eq!(set_allocator(&hooks), CStatus::Ok);
+
+        // A decoded package is a single allocation C can free on its own
+        let mut package : *mut CPackage = ptr::null_mut();
+        assert_eq!(decode_package(PACKAGE.as_ptr(), PACKAGE.len(), &mut package), CStatus::Ok);
+        assert_eq!(live(), 1);
+        assert_eq!((package as usize) % std::mem::align_of::<CPackage>(), 0);
+        let mut len : usize = 0;
+        let query = package_query(package, &mut len);
+        assert_eq!(slice::from_raw_parts(query as *const u8, len + 1), b"hello\0");
+        let payload = package_payload(package, &mut len);
+        assert_eq!(slice::from_raw_parts(payload as *const u8, len + 1), b"!\0");
+        assert_eq!((*package).extensions_len, 5);
+
+        // So is an encoded buffer
+        let mut buffer : *mut u8 = ptr::null_mut();
+        assert_eq!(encode_package(package, &mut buffer, &mut len), CStatus::Ok);
+        assert_eq!(live(), 2);
+        assert_eq!(slice::from_raw_parts(buffer, len), &PACKAGE[..PACKAGE_LEN]);
+
+        arena_free(hooks.context, buffer as *mut c_void);
+        arena_free(hooks.context, package as *mut c_void);
+        assert_eq!(live(), 0);
+
+        // The library's own free functions go through the hooks as well
+        assert_eq!(decode_package(PACKAGE.as_ptr(), PACKAGE.len(), &mut package), CStatus::Ok);
+        assert_eq!(encode_package(package, &mut buffer, &mut len), CStatus::Ok);
+        assert_eq!(live(), 2);
+        free_buffer(buffer, len);
+        free_package(package);
+        assert_eq!(live(), 0);
+
+        // Allocation failures are reported, not swallowed
+        arena.fail.store(true, Ordering::SeqCst);
+        assert_eq!(decode_package(PACKAGE.as_ptr(), PACKAGE.len(), &mut package), CStatus::OutOfMemory);
+        assert!(package.is_null());
+        arena.fail.store(false, Ordering::SeqCst);
+
+        // Back to the built-in allocator
+        assert_eq!(set_allocator(ptr::null()), CStatus::Ok);
+        assert_eq!(decode_package(PACKAGE.as_ptr(), PACKAGE.len(), &mut package), CStatus::Ok);
+        assert_eq!(live(), 0);
+        free_package(package);
+    }
+}
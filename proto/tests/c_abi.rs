@@ -0,0 +1,141 @@
+//! Exercise the C API the way a C caller would, checking that every
+//! allocation handed out through it is given back.
+
+extern crate fancy_talk;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::ffi::CStr;
+use std::ptr;
+use std::slice;
+
+use fancy_talk::*;
+
+/// Allocator keeping track of the bytes currently allocated by each thread
+struct CountingAllocator;
+
+thread_local! {
+    static LIVE_BYTES: Cell<isize> = const { Cell::new(0) };
+}
+
+fn track(delta: isize) {
+    let _ = LIVE_BYTES.try_with(|live| live.set(live.get() + delta));
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            track(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        track(-(layout.size() as isize));
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn live_bytes() -> isize {
+    LIVE_BYTES.with(|live| live.get())
+}
+
+/// Run `f` and check it didn't leave anything allocated behind
+fn assert_no_leaks<F: FnOnce()>(f: F) {
+    let before = live_bytes();
+    f();
+    assert_eq!(live_bytes() - before, 0, "C API leaked memory");
+}
+
+const PACKAGE: [u8; 29] = [
+    0x23, 0x42,  // ID
+    0b0100_0100, // query, bold, extensions
+    0x12, 0x34, 0x56,
+    0x00, 0x05,  // len
+    b'h', b'e', b'l', b'l', b'o',
+    0x00, 0x05,  // len
+    b'w', b'o', b'r', b'l', b'd',
+    0x00, 0x05,  // extension block len
+    0x00, 0x01,  // kind: version
+    0x00, 0x01,  // len
+    0x01,
+    0x00, 0x00,  // padding, not part of the package
+];
+
+const PACKAGE_LEN: usize = 27;
+
+unsafe fn decode(buffer: &[u8]) -> *mut CPackage {
+    let mut package : *mut CPackage = ptr::null_mut();
+    assert_eq!(decode_package(buffer.as_ptr(), buffer.len(), &mut package), CStatus::Ok);
+    assert!(!package.is_null());
+    package
+}
+
+unsafe fn borrowed(data: *const std::os::raw::c_char, len: usize) -> &'static [u8] {
+    assert!(!data.is_null());
+    // Decoded strings come NUL-terminated
+    assert_eq!(*data.add(len), 0);
+    assert_eq!(CStr::from_ptr(data).to_bytes().len(), len);
+    slice::from_raw_parts(data as *const u8, len)
+}
+
+#[test]
+fn test_c_abi_roundtrip() {
+    // Warm up lazily allocated state like the last error message
+    unsafe {
+        let mut package : *mut CPackage = ptr::null_mut();
+        decode_package(PACKAGE.as_ptr(), 3, &mut package);
+    }
+
+    // decode and free
+    assert_no_leaks(|| unsafe {
+        let package = decode(&PACKAGE);
+        let mut len : usize = 0;
+        assert_eq!(borrowed(package_query(package, &mut len), len), b"hello");
+        assert_eq!(borrowed(package_payload(package, &mut len), len), b"world");
+        assert_eq!((*package).extensions_len, 5);
+        free_package(package);
+    });
+
+    // decode, encode with a library-allocated buffer, free both
+    assert_no_leaks(|| unsafe {
+        let package = decode(&PACKAGE);
+        let mut buffer : *mut u8 = ptr::null_mut();
+        let mut len : usize = 0;
+        assert_eq!(encode_package(package, &mut buffer, &mut len), CStatus::Ok);
+        assert_eq!(slice::from_raw_parts(buffer, len), &PACKAGE[..PACKAGE_LEN]);
+        free_buffer(buffer, len);
+        free_package(package);
+    });
+
+    // decode, encode into a caller-owned buffer
+    assert_no_leaks(|| unsafe {
+        let package = decode(&PACKAGE);
+        let mut buffer = [0u8; 64];
+        let mut written : usize = 0;
+        assert_eq!(encode_package_into(package, buffer.as_mut_ptr(), buffer.len(), &mut written), CStatus::Ok);
+        assert_eq!(&buffer[..written], &PACKAGE[..PACKAGE_LEN]);
+        free_package(package);
+    });
+
+    // failed calls don't leak either
+    assert_no_leaks(|| unsafe {
+        let mut package : *mut CPackage = ptr::null_mut();
+        assert_eq!(decode_package(PACKAGE.as_ptr(), 3, &mut package), CStatus::Truncated);
+        assert!(package.is_null());
+    });
+
+    // no query or payload at all
+    assert_no_leaks(|| unsafe {
+        let package = decode(&[0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let mut len : usize = 42;
+        assert!(package_query(package, &mut len).is_null());
+        assert_eq!(len, 0);
+        assert!(package_payload(package, ptr::null_mut()).is_null());
+        free_package(package);
+    });
+}
@@ -0,0 +1,181 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use log::LevelFilter;
+
+pub const DEFAULT_PORT : u16 = 65432;
+
+const ADDRESS_HELP : &str = "\
+Addresses:
+  udp:HOST:PORT      UDP, one package per datagram
+  tcp:HOST:PORT      TCP, packages preceded by their length
+  unix:PATH          Unix stream socket, framed like TCP
+  unixgram:PATH      Unix datagram socket
+
+Without any addresses or --bind, listens on UDP on 127.0.0.1 (::1 with --ipv6).
+--port and --tcp only apply to --bind and that default address, --ipv6 only
+to the default, so they are rejected where they would be ignored.
+Send SIGHUP to reload the catalogue file.";
+
+/// Server for the fancy-talk demo protocol
+#[derive(Debug, Parser)]
+#[command(version, about, after_help = ADDRESS_HELP)]
+pub struct Args {
+    /// Addresses to listen on, see below
+    #[arg(value_name = "ADDRESS")]
+    pub listen: Vec<Listen>,
+
+    /// IP address to listen on, can be given multiple times
+    #[arg(short, long, value_name = "IP")]
+    pub bind: Vec<IpAddr>,
+
+    /// Port to listen on for --bind and the default address [default: 65432]
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// Listen on ::1 instead of 127.0.0.1 by default
+    #[arg(short = '6', long, conflicts_with_all = ["listen", "bind"])]
+    pub ipv6: bool,
+
+    /// Listen on TCP as well as UDP for the --bind addresses
+    #[arg(long)]
+    pub tcp: bool,
+
+    /// Load the messages from this TOML file instead of the built-in catalogue
+    #[arg(short, long, value_name = "PATH")]
+    pub catalogue: Option<PathBuf>,
+
+    /// Log messages up to this level: off, error, warn, info, debug or trace [default: info, or RUST_LOG]
+    #[arg(short, long, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+
+    /// Answer "exit" queries without shutting down
+    #[arg(long)]
+    pub no_exit: bool,
+}
+
+impl Args {
+    /// Parse `args` like `try_parse_from()`, rejecting options that would be ignored
+    pub fn try_parse_checked<I, T>(args: I) -> Result<Args, clap::Error>
+        where I: IntoIterator<Item = T>, T: Into<OsString> + Clone
+    {
+        let args = Args::try_parse_from(args)?;
+        if !args.listen.is_empty() && args.bind.is_empty() {
+            let ignored = match (args.port.is_some(), args.tcp) {
+                (true, _) => Some(("--port", "put the port into each ADDRESS instead")),
+                (false, true) => Some(("--tcp", "add tcp:HOST:PORT addresses instead")),
+                (false, false) => None,
+            };
+            if let Some((option, hint)) = ignored {
+                let message = format!("{} only applies to --bind and the default address, {}", option, hint);
+                return Err(Args::command().error(ErrorKind::ArgumentConflict, message));
+            }
+        }
+        Ok(args)
+    }
+
+    /// All addresses to listen on, with the defaults filled in
+    pub fn listen_addresses(&self) -> Vec<Listen> {
+        let mut addresses = self.listen.clone();
+
+        let mut bind = self.bind.clone();
+        if bind.is_empty() && self.listen.is_empty() {
+            bind.push(if self.ipv6 { IpAddr::V6(Ipv6Addr::LOCALHOST) } else { IpAddr::V4(Ipv4Addr::LOCALHOST) });
+        }
+        for ip in bind {
+            let address = SocketAddr::new(ip, self.port.unwrap_or(DEFAULT_PORT)).to_string();
+            if self.tcp {
+                addresses.push(Listen::Tcp(address.clone()));
+            }
+            addresses.push(Listen::Udp(address));
+        }
+        addresses
+    }
+}
+
+/// An address to listen on
+#[derive(Clone, Debug, PartialEq)]
+pub enum Listen {
+    Udp(String),
+    Tcp(String),
+    Unix(PathBuf),
+    UnixDatagram(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Listen, String> {
+        match s.split_once(':') {
+            Some(("udp", address)) => Ok(Listen::Udp(String::from(address))),
+            Some(("tcp", address)) => Ok(Listen::Tcp(String::from(address))),
+            Some(("unix", path)) if !path.is_empty() => Ok(Listen::Unix(PathBuf::from(path))),
+            Some(("unixgram", path)) if !path.is_empty() => Ok(Listen::UnixDatagram(PathBuf::from(path))),
+            _ => Err(String::from("use udp:HOST:PORT, tcp:HOST:PORT, unix:PATH or unixgram:PATH")),
+        }
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Listen::Udp(ref address) => write!(f, "udp:{}", address),
+            Listen::Tcp(ref address) => write!(f, "tcp:{}", address),
+            Listen::Unix(ref path) => write!(f, "unix:{}", path.display()),
+            Listen::UnixDatagram(ref path) => write!(f, "unixgram:{}", path.display()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_checked(Some("fancy-talk-server").into_iter().chain(args.iter().cloned()))
+    }
+
+    fn listen(args: &[&str]) -> Vec<String> {
+        parse(args).unwrap().listen_addresses().iter().map(Listen::to_string).collect()
+    }
+
+    #[test]
+    fn test_command() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn test_listen_addresses() {
+        assert_eq!(listen(&[]), ["udp:127.0.0.1:65432"]);
+        assert_eq!(listen(&["-6", "-p", "7000"]), ["udp:[::1]:7000"]);
+        assert_eq!(listen(&["--tcp", "-b", "0.0.0.0", "-b", "::"]),
+                   ["tcp:0.0.0.0:65432", "udp:0.0.0.0:65432", "tcp:[::]:65432", "udp:[::]:65432"]);
+        assert_eq!(listen(&["unix:/tmp/fancy.sock", "tcp:localhost:7000"]),
+                   ["unix:/tmp/fancy.sock", "tcp:localhost:7000"]);
+        assert_eq!(listen(&["unixgram:/tmp/fancy.sock", "-b", "127.0.0.1", "-p", "7000"]),
+                   ["unixgram:/tmp/fancy.sock", "udp:127.0.0.1:7000"]);
+    }
+
+    #[test]
+    fn test_ignored_options() {
+        for args in [&["-6", "udp:127.0.0.1:7000"][..], &["-6", "-b", "127.0.0.1"],
+                     &["-p", "7000", "udp:127.0.0.1:7000"], &["--tcp", "unix:/tmp/fancy.sock"]] {
+            let err = parse(args).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ArgumentConflict, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Args::try_parse_from(["fancy-talk-server", "sctp:127.0.0.1:1"]).is_err());
+        assert!(Args::try_parse_from(["fancy-talk-server", "unix:"]).is_err());
+        assert!(Args::try_parse_from(["fancy-talk-server", "-b", "localhost"]).is_err());
+        assert!(Args::try_parse_from(["fancy-talk-server", "-l", "chatty"]).is_err());
+    }
+}
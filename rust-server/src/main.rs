@@ -1,78 +1,432 @@
-extern crate fancy_talk;
-use std::net::UdpSocket;
-use std::collections::HashMap;
-use fancy_talk::{Package, MessageType, Decoder, Encoder, Serialisable};
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::net as std_unix;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
 
+use bytes::BytesMut;
+use fancy_talk::{Catalogue, Package, PackageRef, Decoder, Encoder, Error, Serialisable, PackageCodec,
+                 CodecError, MAX_DATAGRAM_LEN};
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UdpSocket, UnixDatagram, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio_util::codec::{self, Framed};
 
-const MAX_UDP_SIZE : usize = 4096;
+use crate::cli::{Args, Listen};
 
-fn lookup_message(messages: &HashMap<&str, Package>, query: &Package) -> Package {
+mod cli;
 
-    let query_text = match query.query {
-        None => String::from("fallback"),
-        Some(ref text) => String::from(text.as_str()),
-    };
+/// State shared by all listeners
+struct Server {
+    /// Replaced as a whole when the catalogue is reloaded
+    catalogue: RwLock<Catalogue>,
+    stats: Stats,
+    /// Signals `main()` that a client asked the server to exit
+    exit: UnboundedSender<()>,
+    /// Whether "exit" queries shut down the server
+    honour_exit: bool,
+}
 
-    let resp_query = match query.query {
-        None => None,
-        Some(ref text) => Some(String::from(text.as_str())),
-    };
-    let mut resp = match messages.get(query_text.as_str()) {
-        None => messages.get("fallback").unwrap().clone(),
-        Some(response) => response.clone(),
+#[derive(Default)]
+struct Stats {
+    queries: AtomicU64,
+    malformed: AtomicU64,
+}
+
+impl Server {
+    /// Answer a query that decoded fine
+    fn answer(&self, id: u16, query: Option<&str>, version: Option<u8>, peer: &str) -> Package {
+        debug!("Query {:?} from {}", query, peer);
+        self.stats.queries.fetch_add(1, Ordering::Relaxed);
+        self.catalogue.read().unwrap().answer(id, query, version)
+    }
+
+    /// Answer a query that failed to decode with `err`
+    fn answer_malformed(&self, id: u16, err: &Error, peer: &str) -> Package {
+        warn!("Malformed query from {}: {}", peer, err);
+        self.stats.malformed.fetch_add(1, Ordering::Relaxed);
+        Package::error_response(id, err)
+    }
+
+    /// Re-read the catalogue from `path`, keeping the current one if that fails
+    fn reload(&self, path: &Path) {
+        match Catalogue::load(path) {
+            Ok(catalogue) => {
+                info!("Reloaded {} messages from {}", catalogue.len(), path.display());
+                *self.catalogue.write().unwrap() = catalogue;
+            },
+            Err(e) => error!("Reloading catalogue {} failed, keeping the old one: {}", path.display(), e),
+        }
+    }
+
+    fn request_exit(&self) {
+        if self.honour_exit {
+            let _ = self.exit.send(());
+        } else {
+            info!("Ignoring exit request, exit queries are disabled");
+        }
+    }
+}
+
+/// Clients can shut down the server with this query
+fn is_exit(query: Option<&str>) -> bool {
+    query == Some("exit")
+}
+
+/// Answer the query in `datagram` from `peer`
+///
+/// Returns the encoded response and whether the client asked the server to
+/// exit, or `None` if the response could not be encoded.
+fn answer_datagram(datagram: &[u8], peer: &str, server: &Server) -> Option<(Vec<u8>, bool)> {
+    let mut decoder = Decoder::new(datagram);
+
+    let (response, exit) = match PackageRef::read(&mut decoder) {
+        Ok(query) => (server.answer(query.id, query.query, query.version(), peer), is_exit(query.query)),
+        Err(e) => {
+            // Answer with the query's ID if we got that far
+            let id = Decoder::new(datagram).read_u16().unwrap_or(0);
+            (server.answer_malformed(id, &e, peer), false)
+        },
     };
-    resp.id = query.id;
-    resp.query = resp_query;
-    resp
-}
-
-fn main() {
-    let socket = UdpSocket::bind("127.0.0.1:65432").expect("Binding to the socket failed");
-    let greeting = Package::new().set_italic(true).set_rgb(0xEE, 0x66, 0x22)
-                                 .set_payload(Some(String::from("Hello, world!")))
-                                 .set_message_type(MessageType::Response);
-    let hamlet = Package::new().set_underlined(true).set_rgb(0x00, 0x66, 0x66)
-                               .set_payload(Some(String::from("Alas, poor Yorick!")))
-                               .set_message_type(MessageType::Response);
-    let farewell = Package::new().set_bold(true).set_rgb(0x00, 0x22, 0x66)
-                                 .set_payload(Some(String::from("Time to sahay goooooodbyeeeeeee!!!!")))
-                                 .set_message_type(MessageType::Response);
-    let fallback = Package::new().set_bold(true).set_blink(true).set_rgb(0xff, 0x00, 0x00)
-                                 .set_message_type(MessageType::Response)
-                                 .set_payload(Some(String::from("Not found!")));
-    let exit = Package::new().set_bold(true).set_italic(true).set_rgb(0x00, 0xcc, 0x00)
-                                 .set_message_type(MessageType::Response)
-                                 .set_payload(Some(String::from("Bye, bye.")));
-
-    let mut messages = HashMap::new();
-    messages.insert("greeting", greeting);
-    messages.insert("hamlet", hamlet);
-    messages.insert("farewell", farewell);
-    messages.insert("fallback", fallback);
-    messages.insert("exit", exit);
 
+    let mut outbuf: Vec<u8> = Vec::new();
+
+    {
+        let mut encoder = Encoder::new(&mut outbuf);
+        if let Err(e) = response.write(&mut encoder) {
+            error!("Encoding response for {} failed: {}", peer, e);
+            return None;
+        }
+    }
+
+    Some((outbuf, exit))
+}
+
+/// Answer queries on `socket`, one package per datagram
+///
+/// Every datagram is handled in its own task.
+async fn serve_udp(socket: UdpSocket, server: Arc<Server>) {
+    let socket = Arc::new(socket);
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+    loop {
+        let (amt, src) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Recv from socket failed: {}", e);
+                continue;
+            },
+        };
+        let datagram = buf[..amt].to_vec();
+
+        let socket = socket.clone();
+        let server = server.clone();
+        tokio::spawn(async move {
+            let peer = src.to_string();
+            if let Some((outbuf, exit_requested)) = answer_datagram(&datagram, &peer, &server) {
+                if let Err(e) = socket.send_to(&outbuf, src).await {
+                    warn!("Sending reply to {} failed: {}", peer, e);
+                }
+                if exit_requested {
+                    server.request_exit();
+                }
+            }
+        });
+    }
+}
+
+/// Like `serve_udp()`, on a Unix datagram socket
+async fn serve_unix_datagram(socket: UnixDatagram, server: Arc<Server>) {
+    let socket = Arc::new(socket);
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
     loop {
-        let mut buf : [u8; MAX_UDP_SIZE] = [0; MAX_UDP_SIZE];
-        let (amt, src) = socket.recv_from(&mut buf).expect("Recv from socket failed");
-        let buf = &mut buf[..amt];
-        let mut decoder = Decoder::new(buf);
+        let (amt, src) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Recv from socket failed: {}", e);
+                continue;
+            },
+        };
+        let datagram = buf[..amt].to_vec();
 
-        let query = Package::read(&mut decoder).expect("Parsing query failed");
-        let response = lookup_message(&mut messages, &query);
+        // Clients need to bind their socket to a path to get an answer
+        let path = match src.as_pathname() {
+            Some(path) => path.to_path_buf(),
+            None => {
+                warn!("Can't reply to a client on an unnamed socket");
+                continue;
+            },
+        };
 
-        let mut outbuf: Vec<u8> = Vec::new();
+        let socket = socket.clone();
+        let server = server.clone();
+        tokio::spawn(async move {
+            let peer = path.display().to_string();
+            if let Some((outbuf, exit_requested)) = answer_datagram(&datagram, &peer, &server) {
+                if let Err(e) = socket.send_to(&outbuf, &path).await {
+                    warn!("Sending reply to {} failed: {}", peer, e);
+                }
+                if exit_requested {
+                    server.request_exit();
+                }
+            }
+        });
+    }
+}
 
-        {
-            let mut encoder = Encoder::new(&mut outbuf);
-            response.write(&mut encoder).expect("Encoding response failed");
+/// Accept TCP connections, each handled in its own task
+async fn serve_tcp(listener: TcpListener, server: Arc<Server>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(handle_connection(stream, peer.to_string(), server.clone()));
+            },
+            Err(e) => warn!("Accepting connection failed: {}", e),
         }
+    }
+}
 
-        socket.send_to(outbuf.as_slice(), &src).expect("Sending reply failed");
+/// Like `serve_tcp()`, on a Unix stream socket
+async fn serve_unix(listener: UnixListener, server: Arc<Server>) {
+    let mut connections = 0u64;
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                // Unix stream clients are usually unnamed, number them for the logs
+                connections += 1;
+                let peer = format!("unix client #{}", connections);
+                tokio::spawn(handle_connection(stream, peer, server.clone()));
+            },
+            Err(e) => warn!("Accepting connection failed: {}", e),
+        }
+    }
+}
 
-        if let Some(q) = query.query {
-            if q == "exit" {
-                break;
+/// `PackageCodec` that remembers the ID of the last frame it decoded
+///
+/// The codec consumes a malformed frame along with the error, this keeps
+/// its ID so the error response can carry it like on datagram transports.
+struct QueryCodec {
+    codec: PackageCodec,
+    last_id: u16,
+}
+
+impl codec::Decoder for QueryCodec {
+    type Item = Package;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Package>, CodecError> {
+        // The ID follows the u32 length prefix
+        self.last_id = Decoder::new(src.get(4..).unwrap_or(&[])).read_u16().unwrap_or(0);
+        self.codec.decode(src)
+    }
+}
+
+impl codec::Encoder<Package> for QueryCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, package: Package, dst: &mut BytesMut) -> Result<(), CodecError> {
+        self.codec.encode(package, dst)
+    }
+}
+
+/// Answer length-prefixed queries on `stream` until the client hangs up
+///
+/// A malformed query is answered with an error response, then the
+/// connection is closed, as the framing can't be trusted after that.
+async fn handle_connection<S>(stream: S, peer: String, server: Arc<Server>)
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    debug!("Connection from {}", peer);
+    let mut framed = Framed::new(stream, QueryCodec { codec: PackageCodec::new(), last_id: 0 });
+    while let Some(query) = framed.next().await {
+        let query = match query {
+            Ok(query) => query,
+            Err(CodecError::Package(e)) => {
+                // Answer with the query's ID if we got that far
+                let response = server.answer_malformed(framed.codec().last_id, &e, &peer);
+                let _ = framed.send(response).await;
+                debug!("Closing connection to {} after malformed query", peer);
+                return;
+            },
+            Err(e) => {
+                warn!("Connection to {} failed: {}", peer, e);
+                return;
+            },
+        };
+        let response = server.answer(query.id, query.query.as_deref(), query.version(), &peer);
+
+        if let Err(e) = framed.send(response).await {
+            warn!("Sending reply to {} failed: {}", peer, e);
+            return;
+        }
+
+        if is_exit(query.query.as_deref()) {
+            server.request_exit();
+        }
+    }
+    debug!("Connection from {} closed", peer);
+}
+
+/// Bind to `address` and answer queries on it in the background
+async fn listen(address: &Listen, server: Arc<Server>) -> io::Result<()> {
+    match *address {
+        Listen::Udp(ref address) => {
+            let socket = UdpSocket::bind(address).await?;
+            tokio::spawn(serve_udp(socket, server));
+        },
+        Listen::Tcp(ref address) => {
+            let listener = TcpListener::bind(address).await?;
+            tokio::spawn(serve_tcp(listener, server));
+        },
+        Listen::Unix(ref path) => {
+            remove_stale_socket(path, |path| std_unix::UnixStream::connect(path).map(drop))?;
+            let listener = UnixListener::bind(path)?;
+            tokio::spawn(serve_unix(listener, server));
+        },
+        Listen::UnixDatagram(ref path) => {
+            remove_stale_socket(path, |path| std_unix::UnixDatagram::unbound()?.connect(path))?;
+            let socket = UnixDatagram::bind(path)?;
+            tokio::spawn(serve_unix_datagram(socket, server));
+        },
+    }
+    Ok(())
+}
+
+/// Remove a socket file left behind by an earlier run, so we can bind to `path`
+///
+/// `connect` probes the socket, the file is only removed if nobody is
+/// listening on it any more. Fails if another server still is.
+fn remove_stale_socket<F>(path: &Path, connect: F) -> io::Result<()>
+    where F: FnOnce(&Path) -> io::Result<()>
+{
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => (),
+        // Binding reports anything else in the way
+        _ => return Ok(()),
+    }
+    match connect(path) {
+        Ok(()) => Err(io::Error::new(io::ErrorKind::AddrInUse, "address in use by another server")),
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(_) => Ok(()),
+    }
+}
+
+/// A socket file we bound, removed again on shutdown
+struct SocketFile {
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+}
+
+impl SocketFile {
+    fn new(path: &Path) -> io::Result<SocketFile> {
+        let metadata = fs::symlink_metadata(path)?;
+        Ok(SocketFile { path: path.to_path_buf(), dev: metadata.dev(), ino: metadata.ino() })
+    }
+
+    /// Remove the file unless it was replaced by someone else's since we bound it
+    fn remove(self) {
+        match fs::symlink_metadata(&self.path) {
+            Ok(metadata) if metadata.dev() == self.dev && metadata.ino() == self.ino => {
+                if let Err(e) = fs::remove_file(&self.path) {
+                    warn!("Removing {} failed: {}", self.path.display(), e);
+                }
+            },
+            _ => debug!("Leaving {} alone, it is not the socket we bound", self.path.display()),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::try_parse_checked(env::args_os()).unwrap_or_else(|e| e.exit());
+
+    let mut logger = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if let Some(level) = args.log_level {
+        logger.filter_level(level);
+    }
+    logger.init();
+
+    let catalogue = match args.catalogue {
+        Some(ref path) => match Catalogue::load(path) {
+            Ok(catalogue) => {
+                info!("Loaded {} messages from {}", catalogue.len(), path.display());
+                catalogue
+            },
+            Err(e) => {
+                error!("Loading catalogue {} failed: {}", path.display(), e);
+                process::exit(1);
+            },
+        },
+        None => Catalogue::default(),
+    };
+
+    let (exit, mut exit_requested) = mpsc::unbounded_channel();
+    let server = Arc::new(Server {
+        catalogue: RwLock::new(catalogue),
+        stats: Stats::default(),
+        exit,
+        honour_exit: !args.no_exit,
+    });
+
+    // Install the handlers before answering anything, a signal sent as soon
+    // as we answer must not kill us before we clean up
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Installing the SIGINT handler failed");
+    let mut terminate = signal(SignalKind::terminate()).expect("Installing the SIGTERM handler failed");
+    let mut hangup = signal(SignalKind::hangup()).expect("Installing the SIGHUP handler failed");
+
+    let mut socket_files: Vec<SocketFile> = Vec::new();
+    for address in args.listen_addresses() {
+        let server = server.clone();
+        if let Err(e) = listen(&address, server).await {
+            error!("Binding to {} failed: {}", address, e);
+            for file in socket_files {
+                file.remove();
+            }
+            process::exit(1);
+        }
+        if let Listen::Unix(ref path) | Listen::UnixDatagram(ref path) = address {
+            match SocketFile::new(path) {
+                Ok(file) => socket_files.push(file),
+                Err(e) => warn!("Can't find {} to clean up later: {}", path.display(), e),
             }
         }
+        info!("Listening on {}", address);
+    }
+
+    // Run until a client asks us to exit or we get told to stop, SIGHUP
+    // reloads the catalogue
+    loop {
+        tokio::select! {
+            _ = exit_requested.recv() => {
+                info!("Exit requested by a client, shutting down");
+                break;
+            },
+            _ = interrupt.recv() => {
+                info!("Got SIGINT, shutting down");
+                break;
+            },
+            _ = terminate.recv() => {
+                info!("Got SIGTERM, shutting down");
+                break;
+            },
+            _ = hangup.recv() => match args.catalogue {
+                Some(ref path) => server.reload(path),
+                None => warn!("Got SIGHUP, but there is no catalogue file to reload"),
+            },
+        }
+    }
+    for file in socket_files {
+        file.remove();
     }
+    info!("Answered {} queries, {} of them malformed",
+          server.stats.queries.load(Ordering::Relaxed) + server.stats.malformed.load(Ordering::Relaxed),
+          server.stats.malformed.load(Ordering::Relaxed));
 }
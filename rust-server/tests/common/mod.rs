@@ -0,0 +1,133 @@
+//! Helpers for running the server in integration tests
+
+// Not every test binary uses every helper
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fancy_talk::{Decoder, Encoder, Package, Serialisable, MAX_DATAGRAM_LEN};
+
+/// Kills the server if a test fails before it exits on its own
+pub struct ServerProcess(pub Child);
+
+impl ServerProcess {
+    /// Start the server with `args` and wait until it answers on `udp_port`
+    ///
+    /// The server binds its addresses in order, so `udp_port` should be the
+    /// last one for the others to be ready too. Returns the server along
+    /// with a socket connected to it.
+    pub fn start(args: &[String], udp_port: u16) -> (ServerProcess, UdpSocket) {
+        ServerProcess::start_at(args, SocketAddr::from(([127, 0, 0, 1], udp_port)))
+    }
+
+    /// Like `start()`, waiting for an answer on `address` instead
+    pub fn start_at(args: &[String], address: SocketAddr) -> (ServerProcess, UdpSocket) {
+        let child = Command::new(env!("CARGO_BIN_EXE_fancy-talk-server"))
+            .args(args)
+            .env("RUST_LOG", "error")
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("starting the server failed");
+        let server = ServerProcess(child);
+
+        let local = match address {
+            SocketAddr::V4(_) => "127.0.0.1:0",
+            SocketAddr::V6(_) => "[::1]:0",
+        };
+        let socket = UdpSocket::bind(local).unwrap();
+        socket.connect(address).unwrap();
+
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let started = Instant::now();
+        loop {
+            let mut buf = [0u8; 4096];
+            socket.send(&query(1, "greeting")).unwrap();
+            if socket.recv(&mut buf).is_ok() {
+                break;
+            }
+            assert!(started.elapsed() < Duration::from_secs(10), "server did not start");
+        }
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        (server, socket)
+    }
+
+    /// Send `signal` to the server, e.g. "HUP"
+    pub fn signal(&self, signal: &str) {
+        let status = Command::new("kill")
+            .arg(format!("-{}", signal))
+            .arg(self.0.id().to_string())
+            .status()
+            .expect("running kill failed");
+        assert!(status.success());
+    }
+
+    /// Wait for the server to exit and check it exited cleanly
+    pub fn wait_for_exit(&mut self) {
+        let started = Instant::now();
+        loop {
+            if let Some(status) = self.0.try_wait().unwrap() {
+                assert!(status.success());
+                return;
+            }
+            assert!(started.elapsed() < Duration::from_secs(10), "server did not exit");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+pub fn encode(package: &Package) -> Vec<u8> {
+    let mut data: Vec<u8> = Vec::new();
+    package.write(&mut Encoder::new(&mut data)).unwrap();
+    data
+}
+
+pub fn query(id: u16, query: &str) -> Vec<u8> {
+    let mut package = Package::new().set_id(id).set_version(1);
+    package.set_query(Some(String::from(query)));
+    encode(&package)
+}
+
+/// Send `datagram` and decode the answer
+pub fn exchange(socket: &UdpSocket, datagram: &[u8]) -> Package {
+    socket.send(datagram).unwrap();
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+    let amt = socket.recv(&mut buf).expect("no answer from the server");
+    Package::read(&mut Decoder::new(&buf[..amt])).expect("server sent a malformed answer")
+}
+
+/// Send `datagram` on a stream, preceded by its length like the TCP transport
+pub fn send_framed<S: Write>(stream: &mut S, datagram: &[u8]) {
+    stream.write_all(&(datagram.len() as u32).to_be_bytes()).unwrap();
+    stream.write_all(datagram).unwrap();
+}
+
+/// Read and decode the next length-prefixed answer from a stream
+pub fn receive_framed<S: Read>(stream: &mut S) -> Package {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).expect("no answer from the server");
+    let mut buf = vec![0u8; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf).expect("answer was cut short");
+    Package::read(&mut Decoder::new(&buf)).expect("server sent a malformed answer")
+}
+
+/// Like `exchange()`, on a stream
+pub fn exchange_framed<S: Read + Write>(stream: &mut S, datagram: &[u8]) -> Package {
+    send_framed(stream, datagram);
+    receive_framed(stream)
+}
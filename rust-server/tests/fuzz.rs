@@ -0,0 +1,106 @@
+//! Throws malformed queries at a running server over loopback
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use fancy_talk::{Decoder, MessageType, Package, Serialisable};
+
+use common::{exchange, free_port, query, ServerProcess};
+
+const ROUNDS: usize = 2000;
+
+/// xorshift, so failures can be reproduced
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Random bytes, or a valid query that was truncated or had bytes flipped
+fn fuzz_datagram(rng: &mut Rng) -> Vec<u8> {
+    let mut data = match rng.below(3) {
+        0 => (0..rng.below(64)).map(|_| rng.next() as u8).collect(),
+        _ => query(rng.next() as u16, "greeting"),
+    };
+    if !data.is_empty() && rng.below(2) == 0 {
+        let len = rng.below(data.len());
+        data.truncate(len);
+    }
+    for _ in 0..rng.below(4) {
+        if !data.is_empty() {
+            let i = rng.below(data.len());
+            data[i] ^= rng.next() as u8;
+        }
+    }
+    data
+}
+
+#[test]
+fn test_malformed_queries() {
+    let udp_port = free_port();
+    let tcp_port = free_port();
+    let args = [format!("udp:127.0.0.1:{}", udp_port), format!("tcp:127.0.0.1:{}", tcp_port)];
+    let (mut server, socket) = ServerProcess::start(&args, udp_port);
+
+    let mut rng = Rng(0x2342_1234_5678_9abc);
+    let mut errors = 0;
+    for _ in 0..ROUNDS {
+        let datagram = fuzz_datagram(&mut rng);
+        let answer = exchange(&socket, &datagram);
+        assert_eq!(answer.message_type, MessageType::Response);
+        if answer.error_code().is_some() {
+            errors += 1;
+        } else if answer.query.as_deref() == Some("exit") {
+            panic!("fuzzing produced an exit query, pick another seed");
+        }
+    }
+    assert!(errors > ROUNDS / 4, "only {} of {} queries were rejected", errors, ROUNDS);
+
+    // Error responses keep the ID if the query had one
+    let answer = exchange(&socket, &[0x23, 0x42, 0x00]);
+    assert_eq!(answer.id, 0x2342);
+    assert_eq!(answer.error_code(), Some(1));
+    assert_eq!(exchange(&socket, &[]).id, 0);
+
+    // Still answering valid queries
+    let answer = exchange(&socket, &query(7, "hamlet"));
+    assert_eq!(answer.id, 7);
+    assert_eq!(answer.error_code(), None);
+    assert_eq!(answer.payload.as_deref(), Some("Alas, poor Yorick!"));
+
+    // A stream gets an error response, then gets closed
+    let mut stream = TcpStream::connect(("127.0.0.1", tcp_port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(&[0x00, 0x00, 0x00, 0x03, 0x23, 0x42, 0x00]).unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).unwrap();
+    assert_eq!(&buf[..4], &(buf.len() as u32 - 4).to_be_bytes());
+    let answer = Package::read(&mut Decoder::new(&buf[4..])).unwrap();
+    assert_eq!(answer.id, 0x2342);
+    assert_eq!(answer.error_code(), Some(1));
+
+    // Too short to hold an ID
+    let mut stream = TcpStream::connect(("127.0.0.1", tcp_port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(&[0x00, 0x00, 0x00, 0x01, 0x23]).unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).unwrap();
+    let answer = Package::read(&mut Decoder::new(&buf[4..])).unwrap();
+    assert_eq!(answer.id, 0);
+    assert_eq!(answer.error_code(), Some(1));
+
+    exchange(&socket, &query(8, "exit"));
+    server.wait_for_exit();
+}
@@ -0,0 +1,36 @@
+//! Packages too large for a 4096 byte receive buffer
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use common::{exchange, free_port, query, ServerProcess};
+
+#[test]
+fn test_large_datagrams() {
+    let path = env::temp_dir().join(format!("fancy-talk-large-{}.toml", process::id()));
+    let long = "y".repeat(20000);
+    fs::write(&path, format!("[fallback]\npayload = \"Not found!\"\n\n[long]\npayload = \"{}\"\n", long)).unwrap();
+
+    let udp_port = free_port();
+    let args = [String::from("--catalogue"), path.display().to_string(),
+                format!("udp:127.0.0.1:{}", udp_port)];
+    let (mut server, socket) = ServerProcess::start(&args, udp_port);
+
+    // A large answer
+    let answer = exchange(&socket, &query(1, "long"));
+    assert_eq!(answer.error_code(), None);
+    assert_eq!(answer.payload.as_deref(), Some(&long[..]));
+
+    // A large query is not cut off and taken for a malformed one
+    let answer = exchange(&socket, &query(2, &"z".repeat(10000)));
+    assert_eq!(answer.id, 2);
+    assert_eq!(answer.error_code(), None);
+    assert_eq!(answer.payload.as_deref(), Some("Not found!"));
+
+    exchange(&socket, &query(3, "exit"));
+    server.wait_for_exit();
+    fs::remove_file(&path).unwrap();
+}
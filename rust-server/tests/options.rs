@@ -0,0 +1,65 @@
+//! Command-line options that change how the server behaves
+
+mod common;
+
+use std::net::{Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use common::{exchange, free_port, query, ServerProcess};
+
+#[test]
+fn test_no_exit() {
+    let port = free_port();
+    let args = [String::from("--no-exit"), String::from("-p"), port.to_string()];
+    let (mut server, socket) = ServerProcess::start(&args, port);
+
+    let answer = exchange(&socket, &query(1, "exit"));
+    assert_eq!(answer.payload.as_deref(), Some("Bye, bye."));
+    assert_eq!(exchange(&socket, &query(2, "greeting")).payload.as_deref(), Some("Hello, world!"));
+    assert!(server.0.try_wait().unwrap().is_none());
+
+    server.signal("TERM");
+    server.wait_for_exit();
+}
+
+/// A free UDP port on ::1, `None` if there is no IPv6 loopback
+fn free_ipv6_port() -> Option<u16> {
+    // Not every sandbox has IPv6 loopback
+    let socket = UdpSocket::bind("[::1]:0").ok()?;
+    Some(socket.local_addr().unwrap().port())
+}
+
+#[test]
+fn test_ipv6() {
+    let port = match free_ipv6_port() {
+        Some(port) => port,
+        None => return,
+    };
+
+    let udp_port = free_port();
+    let args = [format!("udp:[::1]:{}", port), format!("udp:127.0.0.1:{}", udp_port)];
+    let (mut server, _) = ServerProcess::start(&args, udp_port);
+
+    let socket = UdpSocket::bind("[::1]:0").unwrap();
+    socket.connect(("::1", port)).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(exchange(&socket, &query(1, "hamlet")).payload.as_deref(), Some("Alas, poor Yorick!"));
+
+    exchange(&socket, &query(2, "exit"));
+    server.wait_for_exit();
+}
+
+#[test]
+fn test_ipv6_default_address() {
+    let port = match free_ipv6_port() {
+        Some(port) => port,
+        None => return,
+    };
+
+    let args = [String::from("-6"), String::from("--port"), port.to_string()];
+    let (mut server, socket) = ServerProcess::start_at(&args, SocketAddr::from((Ipv6Addr::LOCALHOST, port)));
+    assert_eq!(exchange(&socket, &query(1, "hamlet")).payload.as_deref(), Some("Alas, poor Yorick!"));
+
+    exchange(&socket, &query(2, "exit"));
+    server.wait_for_exit();
+}
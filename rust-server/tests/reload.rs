@@ -0,0 +1,51 @@
+//! Reloading the catalogue on SIGHUP
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{exchange, free_port, query, ServerProcess};
+
+const CATALOGUE: &str = "\
+[fallback]
+payload = \"Not found!\"
+
+[greeting]
+payload = \"Hello, world!\"
+";
+
+#[test]
+fn test_reload_on_sighup() {
+    let path = env::temp_dir().join(format!("fancy-talk-reload-{}.toml", std::process::id()));
+    fs::write(&path, CATALOGUE).unwrap();
+
+    let udp_port = free_port();
+    let args = [String::from("--catalogue"), path.display().to_string(),
+                format!("udp:127.0.0.1:{}", udp_port)];
+    let (mut server, socket) = ServerProcess::start(&args, udp_port);
+    let payload = |query_text: &str| exchange(&socket, &query(1, query_text)).payload;
+
+    assert_eq!(payload("greeting").as_deref(), Some("Hello, world!"));
+
+    fs::write(&path, CATALOGUE.replace("Hello, world!", "Hello again!")).unwrap();
+    server.signal("HUP");
+    let started = Instant::now();
+    while payload("greeting").as_deref() != Some("Hello again!") {
+        assert!(started.elapsed() < Duration::from_secs(10), "catalogue was not reloaded");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // An invalid catalogue leaves the old one in place
+    fs::write(&path, "[greeting]\npayload = \"No fallback\"\n").unwrap();
+    server.signal("HUP");
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(payload("greeting").as_deref(), Some("Hello again!"));
+    assert_eq!(payload("hamlet").as_deref(), Some("Not found!"));
+
+    exchange(&socket, &query(2, "exit"));
+    server.wait_for_exit();
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,50 @@
+//! Serving several TCP connections at once and shutting down on SIGTERM
+
+mod common;
+
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use common::{free_port, query, receive_framed, send_framed, ServerProcess};
+
+const QUERIES: [(&str, &str); 3] = [
+    ("greeting", "Hello, world!"),
+    ("hamlet", "Alas, poor Yorick!"),
+    ("farewell", "Time to sahay goooooodbyeeeeeee!!!!"),
+];
+
+#[test]
+fn test_concurrent_connections() {
+    let tcp_port = free_port();
+    let udp_port = free_port();
+    let args = [format!("tcp:127.0.0.1:{}", tcp_port), format!("udp:127.0.0.1:{}", udp_port)];
+    let (mut server, _) = ServerProcess::start(&args, udp_port);
+
+    let mut streams: Vec<TcpStream> = (0..4).map(|_| {
+        let stream = TcpStream::connect(("127.0.0.1", tcp_port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream
+    }).collect();
+
+    // Every connection has queries outstanding before any answer is read
+    for round in 0..QUERIES.len() {
+        for (n, stream) in streams.iter_mut().enumerate() {
+            let (text, _) = QUERIES[(n + round) % QUERIES.len()];
+            send_framed(stream, &query((n * 16 + round) as u16, text));
+        }
+    }
+    for round in 0..QUERIES.len() {
+        for (n, stream) in streams.iter_mut().enumerate() {
+            let (_, payload) = QUERIES[(n + round) % QUERIES.len()];
+            let answer = receive_framed(stream);
+            assert_eq!(answer.id, (n * 16 + round) as u16);
+            assert_eq!(answer.payload.as_deref(), Some(payload));
+        }
+    }
+
+    // Open connections do not hold up the shutdown
+    let started = Instant::now();
+    server.signal("TERM");
+    server.wait_for_exit();
+    assert!(started.elapsed() < Duration::from_secs(2), "server took {:?} to exit", started.elapsed());
+}
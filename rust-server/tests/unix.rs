@@ -0,0 +1,106 @@
+//! Unix stream and datagram sockets, and cleaning up their files
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::time::Duration;
+
+use fancy_talk::{Decoder, Package, Serialisable};
+
+use common::{exchange_framed, free_port, query, ServerProcess};
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("fancy-talk-test-{}-{}", process::id(), name))
+}
+
+/// Check both sockets answer, with the datagram client bound to `reply`
+fn check_answers(stream_path: &Path, datagram_path: &Path, reply: &Path) {
+    let mut stream = UnixStream::connect(stream_path).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let answer = exchange_framed(&mut stream, &query(1, "hamlet"));
+    assert_eq!(answer.id, 1);
+    assert_eq!(answer.payload.as_deref(), Some("Alas, poor Yorick!"));
+
+    // The server can only answer datagram clients bound to a path
+    let _ = fs::remove_file(reply);
+    let socket = UnixDatagram::bind(reply).unwrap();
+    socket.connect(datagram_path).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socket.send(&query(2, "greeting")).unwrap();
+    let mut buf = [0u8; 4096];
+    let amt = socket.recv(&mut buf).expect("no answer from the server");
+    let answer = Package::read(&mut Decoder::new(&buf[..amt])).unwrap();
+    assert_eq!(answer.id, 2);
+    assert_eq!(answer.payload.as_deref(), Some("Hello, world!"));
+    fs::remove_file(reply).unwrap();
+}
+
+#[test]
+fn test_unix_sockets() {
+    let stream_path = temp_path("stream.sock");
+    let datagram_path = temp_path("datagram.sock");
+    let reply = temp_path("reply.sock");
+    let port = free_port();
+    let args = [format!("unix:{}", stream_path.display()),
+                format!("unixgram:{}", datagram_path.display()),
+                format!("udp:127.0.0.1:{}", port)];
+
+    let (server, _) = ServerProcess::start(&args, port);
+    check_answers(&stream_path, &datagram_path, &reply);
+
+    // Killed servers leave their socket files behind
+    drop(server);
+    assert!(stream_path.exists() && datagram_path.exists());
+
+    // and the next one replaces them
+    let (mut server, _) = ServerProcess::start(&args, port);
+    check_answers(&stream_path, &datagram_path, &reply);
+
+    server.signal("TERM");
+    server.wait_for_exit();
+    assert!(!stream_path.exists(), "{} was left behind", stream_path.display());
+    assert!(!datagram_path.exists(), "{} was left behind", datagram_path.display());
+}
+
+#[test]
+fn test_socket_in_use() {
+    let stream_path = temp_path("busy-stream.sock");
+    let datagram_path = temp_path("busy-datagram.sock");
+    let reply = temp_path("busy-reply.sock");
+    let unix_args = [format!("unix:{}", stream_path.display()),
+                     format!("unixgram:{}", datagram_path.display())];
+    let start = |port: u16| {
+        let args = [unix_args[0].clone(), unix_args[1].clone(), format!("udp:127.0.0.1:{}", port)];
+        ServerProcess::start(&args, port).0
+    };
+
+    let mut first = start(free_port());
+
+    // A second server does not take over the sockets of a running one
+    for arg in &unix_args {
+        let status = Command::new(env!("CARGO_BIN_EXE_fancy-talk-server"))
+            .arg(arg)
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert_eq!(status.code(), Some(1), "second server on {}", arg);
+    }
+    check_answers(&stream_path, &datagram_path, &reply);
+
+    // Once the files are replaced, the first server leaves the new ones alone
+    fs::remove_file(&stream_path).unwrap();
+    fs::remove_file(&datagram_path).unwrap();
+    let mut second = start(free_port());
+    first.signal("TERM");
+    first.wait_for_exit();
+    check_answers(&stream_path, &datagram_path, &reply);
+
+    second.signal("TERM");
+    second.wait_for_exit();
+    assert!(!stream_path.exists(), "{} was left behind", stream_path.display());
+    assert!(!datagram_path.exists(), "{} was left behind", datagram_path.display());
+}